@@ -0,0 +1,33 @@
+#!/bin/sh
+# カーネルのELFから関数のシンボル表を作り、.ksymsセクションに書き込む
+# バックトレースで関数名を表示するのに使う (src/backtrace.rs)
+set -e
+
+kernel="$1"
+# src/backtrace.rs の SYMBOL_TABLE_SIZE と揃える
+SIZE=524288
+
+NM=${NM:-$(command -v rust-nm || command -v llvm-nm || echo nm)}
+OBJCOPY=${OBJCOPY:-$(command -v rust-objcopy || command -v llvm-objcopy || echo objcopy)}
+
+table=$(mktemp)
+trap 'rm -f "$table"' EXIT
+
+# "<アドレス> <関数名>" をアドレス順に並べる。入りきらない分は捨てる
+"$NM" -n -C --defined-only "$kernel" |
+    awk -v size="$SIZE" '
+        $2 ~ /^[tTwW]$/ {
+            addr = $1
+            sub(/^[0-9a-fA-F]+ [tTwW] /, "")
+            sub(/::h[0-9a-f]+$/, "")
+            line = addr " " $0 "\n"
+            total += length(line)
+            if (total >= size) exit
+            printf "%s", line
+        }' >"$table"
+
+# セクションの大きさを変えないよう、0で埋めてから書き込む
+truncate -s "$SIZE" "$table"
+if ! "$OBJCOPY" --update-section .ksyms="$table" "$kernel" 2>/dev/null; then
+    echo "warning: could not embed symbols into $kernel" >&2
+fi
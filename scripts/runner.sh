@@ -0,0 +1,7 @@
+#!/bin/sh
+# cargo run / cargo test のランナー
+# シンボル表を埋め込んでから、bootimageでブートイメージを作って起動する
+set -e
+
+"$(dirname "$0")/embed_symbols.sh" "$1"
+exec bootimage runner "$@"
@@ -0,0 +1,8 @@
+#!/bin/sh
+# 全てのアロケータ実装でヒープのテストを実行する
+set -e
+
+for backend in alloc-bump alloc-linked-list alloc-fixed-block; do
+    echo "== $backend"
+    cargo test --no-default-features --features "$backend" --test heap_allocation
+done
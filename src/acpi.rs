@@ -0,0 +1,448 @@
+// ACPIのテーブルを読む
+// ブートローダが全物理メモリをマップしているので、テーブルは`memory::phys_to_virt`で直接読める
+// RSDP → RSDT/XSDT → 各テーブル (MADT, FADT, HPET) の順にたどる
+
+use crate::memory::phys_to_virt;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{mem, ptr, str};
+use x86_64::PhysAddr;
+
+/// テーブルのシグネチャ ("APIC"など)
+pub type Signature = [u8; 4];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    RsdpNotFound,
+    InvalidChecksum(Signature),
+    AlreadyInitialized,
+}
+
+/// テーブルのシグネチャを表示用の文字列にする。
+pub fn signature_str(signature: &Signature) -> &str {
+    str::from_utf8(signature).unwrap_or("????")
+}
+
+// テーブルの先頭にある共通のヘッダの長さ
+const HEADER_SIZE: usize = 36;
+// ACPI 2.0以降のRSDPの長さ
+const RSDP_EXTENDED_SIZE: usize = 36;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    pub signature: Signature,
+    pub address: PhysAddr,
+    pub length: u32,
+}
+
+/// プロセッサのLocal APIC
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    /// このIO-APICの最初のエントリが受け持つGSI
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// バスの規定に従う (ISAならアクティブハイ)
+    Conforming,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// バスの規定に従う (ISAならエッジトリガ)
+    Conforming,
+    Edge,
+    Level,
+}
+
+/// ISAのIRQが別のGSIに繋がっていることを表す
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// Generic Address Structure
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    /// 0 = メモリ、1 = I/Oポート
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// FADTのうち電源の制御に使うレジスタ
+///
+/// `*_block`はI/Oポートの番号で、ないものは0になる。
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    /// RTCの世紀のレジスタ。0ならない
+    pub century_register: u8,
+    /// リセットに使うレジスタと書き込む値
+    pub reset: Option<(GenericAddress, u8)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub base_address: u64,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub vendor_id: u16,
+}
+
+/// ACPIのテーブルから読み取った情報
+#[derive(Debug)]
+pub struct AcpiInfo {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub tables: Vec<TableInfo>,
+    pub local_apic_address: u64,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub interrupt_overrides: Vec<InterruptSourceOverride>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+}
+
+impl AcpiInfo {
+    /// ISAの`irq`のオーバーライドを返す。なければIRQとGSIは同じ番号である。
+    pub fn isa_override(&self, irq: u8) -> Option<&InterruptSourceOverride> {
+        self.interrupt_overrides
+            .iter()
+            .find(|entry| entry.irq == irq)
+    }
+
+    /// `gsi`を受け持つIO-APICを返す。
+    pub fn io_apic_for(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics
+            .iter()
+            .filter(|io_apic| io_apic.gsi_base <= gsi)
+            .max_by_key(|io_apic| io_apic.gsi_base)
+    }
+
+    /// 使用可能なプロセッサの数を返す。
+    pub fn cpu_count(&self) -> usize {
+        self.processors.iter().filter(|cpu| cpu.enabled).count()
+    }
+}
+
+static ACPI: OnceCell<AcpiInfo> = OnceCell::uninit();
+
+/// ACPIのテーブルを探して読み取る。
+///
+/// ヒープを使うので、`allocator::init_heap`の後に呼び出すこと。
+pub fn init() -> Result<(), AcpiError> {
+    let info = unsafe { parse()? };
+    ACPI.try_init_once(|| info)
+        .map_err(|_| AcpiError::AlreadyInitialized)
+}
+
+/// `init`で読み取った情報を返す。
+pub fn info() -> Option<&'static AcpiInfo> {
+    ACPI.try_get().ok()
+}
+
+// この関数はunsafeである：全物理メモリがマップされていなければならない
+unsafe fn parse() -> Result<AcpiInfo, AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let revision: u8 = read(rsdp + 15u64);
+    let oem_id: [u8; 6] = read(rsdp + 9u64);
+
+    // ACPI 2.0以降はXSDT (64bitのアドレス) を使う
+    // 拡張部分を含めた36バイトのチェックサムが合わなければRSDTに戻る
+    let xsdt_address: u64 = if revision >= 2 && checksum(rsdp, RSDP_EXTENDED_SIZE) {
+        read(rsdp + 24u64)
+    } else {
+        if revision >= 2 {
+            log::warn!("ACPI: invalid extended RSDP checksum, using the RSDT");
+        }
+        0
+    };
+    let (root, entry_size) = if xsdt_address != 0 {
+        (Table::new(PhysAddr::new(xsdt_address))?, 8)
+    } else {
+        let rsdt_address: u32 = read(rsdp + 16u64);
+        (Table::new(PhysAddr::new(rsdt_address.into()))?, 4)
+    };
+
+    let mut info = AcpiInfo {
+        revision,
+        oem_id,
+        tables: Vec::new(),
+        local_apic_address: 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        interrupt_overrides: Vec::new(),
+        fadt: None,
+        hpet: None,
+    };
+
+    let entries = (root.length as usize - HEADER_SIZE) / entry_size;
+    for i in 0..entries {
+        let offset = HEADER_SIZE + i * entry_size;
+        let address = if entry_size == 8 {
+            root.read::<u64>(offset)
+        } else {
+            root.read::<u32>(offset).map(u64::from)
+        };
+        let address = match address {
+            Some(address) if address != 0 => PhysAddr::new(address),
+            _ => continue,
+        };
+        // 壊れたテーブルがあっても他のテーブルは読む
+        let table = match Table::new(address) {
+            Ok(table) => table,
+            Err(err) => {
+                log::warn!(
+                    "ACPI: skipping the table at {:#x}: {:?}",
+                    address.as_u64(),
+                    err
+                );
+                continue;
+            }
+        };
+
+        info.tables.push(TableInfo {
+            signature: table.signature(),
+            address: table.phys,
+            length: table.length,
+        });
+        match &table.signature() {
+            b"APIC" => parse_madt(&table, &mut info),
+            b"FACP" => info.fadt = parse_fadt(&table),
+            b"HPET" => info.hpet = parse_hpet(&table),
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+// RSDPを探す
+// EBDAの先頭1KiBと、BIOSの領域 (0xe0000..0x100000) の16バイト境界にある
+unsafe fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(read::<u16>(PhysAddr::new(0x40e))) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+    for &(start, end) in areas.iter().filter(|&&(start, _)| start != 0) {
+        for address in (start..end).step_by(16) {
+            let phys = PhysAddr::new(address);
+            // ACPI 1.0の部分 (20バイト) のチェックサムを確かめる
+            if read::<[u8; 8]>(phys) == *b"RSD PTR " && checksum(phys, 20) {
+                return Some(phys);
+            }
+        }
+    }
+    None
+}
+
+// MADT (Multiple APIC Description Table)
+fn parse_madt(table: &Table, info: &mut AcpiInfo) {
+    info.local_apic_address = table.read::<u32>(HEADER_SIZE).unwrap_or(0).into();
+
+    // 割り込みコントローラのエントリが (種類, 長さ, ...) の形で並んでいる
+    let mut offset = HEADER_SIZE + 8;
+    while let (Some(kind), Some(length)) = (table.read::<u8>(offset), table.read::<u8>(offset + 1))
+    {
+        let length = usize::from(length);
+        if length < 2 {
+            break;
+        }
+        let field = |at: usize| offset + at;
+        match kind {
+            // Processor Local APIC
+            0 => {
+                if let (Some(processor_id), Some(apic_id), Some(flags)) = (
+                    table.read::<u8>(field(2)),
+                    table.read::<u8>(field(3)),
+                    table.read::<u32>(field(4)),
+                ) {
+                    info.processors.push(Processor {
+                        processor_id: processor_id.into(),
+                        apic_id: apic_id.into(),
+                        enabled: flags & 1 != 0,
+                    });
+                }
+            }
+            // IO APIC
+            1 => {
+                if let (Some(id), Some(address), Some(gsi_base)) = (
+                    table.read::<u8>(field(2)),
+                    table.read::<u32>(field(4)),
+                    table.read::<u32>(field(8)),
+                ) {
+                    info.io_apics.push(IoApic {
+                        id,
+                        address: address.into(),
+                        gsi_base,
+                    });
+                }
+            }
+            // Interrupt Source Override
+            2 => {
+                if let (Some(irq), Some(gsi), Some(flags)) = (
+                    table.read::<u8>(field(3)),
+                    table.read::<u32>(field(4)),
+                    table.read::<u16>(field(8)),
+                ) {
+                    info.interrupt_overrides.push(InterruptSourceOverride {
+                        irq,
+                        gsi,
+                        polarity: match flags & 0b11 {
+                            0b01 => Polarity::ActiveHigh,
+                            0b11 => Polarity::ActiveLow,
+                            _ => Polarity::Conforming,
+                        },
+                        trigger: match (flags >> 2) & 0b11 {
+                            0b01 => TriggerMode::Edge,
+                            0b11 => TriggerMode::Level,
+                            _ => TriggerMode::Conforming,
+                        },
+                    });
+                }
+            }
+            // Local APIC Address Override
+            5 => {
+                if let Some(address) = table.read::<u64>(field(4)) {
+                    info.local_apic_address = address;
+                }
+            }
+            // Processor Local x2APIC
+            9 => {
+                if let (Some(apic_id), Some(flags), Some(processor_id)) = (
+                    table.read::<u32>(field(4)),
+                    table.read::<u32>(field(8)),
+                    table.read::<u32>(field(12)),
+                ) {
+                    info.processors.push(Processor {
+                        processor_id,
+                        apic_id,
+                        enabled: flags & 1 != 0,
+                    });
+                }
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+}
+
+// FADT (Fixed ACPI Description Table)
+// 古い版のテーブルは短いので、ないフィールドは読まない
+fn parse_fadt(table: &Table) -> Option<Fadt> {
+    // flagsのRESET_REG_SUP
+    const RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+    let flags = table.read::<u32>(112).unwrap_or(0);
+    let reset = if flags & RESET_REGISTER_SUPPORTED != 0 {
+        table.read_generic_address(116).zip(table.read::<u8>(128))
+    } else {
+        None
+    };
+
+    Some(Fadt {
+        sci_interrupt: table.read(46)?,
+        smi_command_port: table.read(48)?,
+        acpi_enable: table.read(52)?,
+        acpi_disable: table.read(53)?,
+        pm1a_event_block: table.read(56)?,
+        pm1b_event_block: table.read(60)?,
+        pm1a_control_block: table.read(64)?,
+        pm1b_control_block: table.read(68)?,
+        pm_timer_block: table.read(76)?,
+        century_register: table.read(108).unwrap_or(0),
+        reset,
+    })
+}
+
+// HPET (High Precision Event Timer) のテーブル
+fn parse_hpet(table: &Table) -> Option<Hpet> {
+    let block_id: u32 = table.read(HEADER_SIZE)?;
+    let base = table.read_generic_address(40)?;
+    Some(Hpet {
+        base_address: base.address,
+        hpet_number: table.read(52)?,
+        minimum_tick: table.read(53)?,
+        comparators: ((block_id >> 8) & 0x1f) as u8 + 1,
+        counter_64bit: block_id & (1 << 13) != 0,
+        vendor_id: (block_id >> 16) as u16,
+    })
+}
+
+// 物理アドレスにあるテーブル
+struct Table {
+    phys: PhysAddr,
+    length: u32,
+}
+
+impl Table {
+    // この関数はunsafeである：physにはテーブルのヘッダがなければならない
+    unsafe fn new(phys: PhysAddr) -> Result<Table, AcpiError> {
+        let table = Table {
+            phys,
+            length: read(phys + 4u64),
+        };
+        if (table.length as usize) < HEADER_SIZE || !checksum(phys, table.length as usize) {
+            return Err(AcpiError::InvalidChecksum(table.signature()));
+        }
+        Ok(table)
+    }
+
+    fn signature(&self) -> Signature {
+        unsafe { read(self.phys) }
+    }
+
+    // テーブルの範囲外ならNoneを返す
+    fn read<T: Copy>(&self, offset: usize) -> Option<T> {
+        if offset + mem::size_of::<T>() > self.length as usize {
+            return None;
+        }
+        Some(unsafe { read(self.phys + offset as u64) })
+    }
+
+    fn read_generic_address(&self, offset: usize) -> Option<GenericAddress> {
+        Some(GenericAddress {
+            address_space: self.read(offset)?,
+            bit_width: self.read(offset + 1)?,
+            bit_offset: self.read(offset + 2)?,
+            access_size: self.read(offset + 3)?,
+            address: self.read(offset + 4)?,
+        })
+    }
+}
+
+// テーブルはアラインされているとは限らない
+unsafe fn read<T: Copy>(phys: PhysAddr) -> T {
+    ptr::read_unaligned(phys_to_virt(phys).as_ptr())
+}
+
+// 全てのバイトの和が0になっていればよい
+unsafe fn checksum(phys: PhysAddr, length: usize) -> bool {
+    let bytes = core::slice::from_raw_parts(phys_to_virt(phys).as_ptr::<u8>(), length);
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
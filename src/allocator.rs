@@ -1,10 +1,17 @@
+use crate::memory::{self, region, GlobalFrameAllocator};
 #[allow(unused_imports)]
 use alloc::alloc::{GlobalAlloc, Layout};
+#[cfg(feature = "alloc-bump")]
+use bump::BumpAllocator;
 #[allow(unused_imports)]
 use core::ptr::null_mut;
-use fixed_size_block::FixedSizeBlockAllocator;
-// use linked_list_allocator::LockedHeap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "alloc-fixed-block")]
+use fixed_size_block::{FixedSizeBlockAllocator, FixedSizeBlockStats};
+#[cfg(feature = "alloc-linked-list")]
+use linked_list::LinkedListAllocator;
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
     },
@@ -14,6 +21,7 @@ use x86_64::{
 pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
+pub mod stats;
 
 pub struct Dummy;
 
@@ -21,16 +29,51 @@ pub struct Dummy;
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 
 // 100 * 1024byte = 100 * 1KiB = 100KiB
+// 起動時にマップするサイズ、足りなくなればHEAP_MAX_SIZEまで拡張する
 pub const HEAP_SIZE: usize = 100 * 1024;
 
-#[global_allocator]
+// ヒープの上限の初期値 16MiB
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+// 一度に拡張する最小サイズ
+const HEAP_GROW_MIN: usize = 64 * 1024;
+
+// 拡張時に使うHuge Pageのサイズ
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
 // グローバルアロケーターとして登録
-// static ALLOCATOR: Dummy = Dummy;
-// static ALLOCATOR: LockedHeap = LockedHeap::empty();
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
-// static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+// どの実装を使うかはCargo.tomlのalloc-*機能で選ぶ
+#[cfg(feature = "alloc-bump")]
+#[global_allocator]
+static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+
+#[cfg(feature = "alloc-linked-list")]
+#[global_allocator]
+static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+
+#[cfg(feature = "alloc-fixed-block")]
+#[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block"
+)))]
+compile_error!("select an allocator with one of the alloc-bump, alloc-linked-list or alloc-fixed-block features");
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block")
+))]
+compile_error!("only one of the alloc-* features can be enabled; use --no-default-features");
+
 // // unsafe traitを実装する際にはimplブロック全体をunsafeにする(実装自体がunsafe)
 // // alloc_zeroedとreallocはデフォルトで実装済み
 // // Layout構造体 {size, align(メモリの配置がどの境界(バイト数)に揃えられるか)}
@@ -52,11 +95,29 @@ static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAl
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    map_heap_pages(HEAP_START, HEAP_SIZE, mapper, frame_allocator)?;
+
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+    HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::Relaxed);
+
+    Ok(())
+}
+
+// [start, start + size)の範囲のページを新しいフレームにマップする
+// 失敗した場合、それまでにマップしたページはそのまま残る
+fn map_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_start = VirtAddr::new(start as u64);
         // 終端は含まないので -1
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_end = heap_start + size - 1u64;
 
         // アドレスを使用してPage型に変換
         let heap_start_page = Page::containing_address(heap_start);
@@ -75,11 +136,82 @@ pub fn init_heap(
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
 
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    Ok(())
+}
+
+/// ヒープの上限を設定する。
+///
+/// すでにマップ済みの領域より小さくしても縮小はされず、以降の拡張が止まるだけである。
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// ヒープの上限を返す。
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// グローバルアロケータのサイズごとの使用状況を返す。
+#[cfg(feature = "alloc-fixed-block")]
+pub fn block_stats() -> FixedSizeBlockStats {
+    interrupts::without_interrupts(|| ALLOCATOR.lock().stats())
+}
+
+/// 現在マップされているヒープのサイズを返す。
+pub fn heap_size() -> usize {
+    HEAP_END.load(Ordering::Relaxed) - HEAP_START
+}
+
+/// ヒープの終端`heap_end`に続けて、少なくとも`min_size`バイトを追加でマップする。
+///
+/// 実際に追加されたバイト数を返す。途中でフレームが足りなくなった場合は
+/// それまでにマップした分だけを返す。`memory::MAPPER`が初期化されていない場合や
+/// 上限に達した場合、`heap_end`がグローバルアロケータのヒープの終端でない場合
+/// (テスト用のアロケータなど) は`None`を返す。
+/// 呼び出し元はアロケータのロックを保持していること。
+fn grow_heap(heap_end: usize, min_size: usize) -> Option<usize> {
+    if heap_end != HEAP_END.load(Ordering::Relaxed) {
+        return None;
     }
+    let limit = HEAP_START + HEAP_LIMIT.load(Ordering::Relaxed);
 
-    Ok(())
+    let size = align_up(min_size.max(HEAP_GROW_MIN), PAGE_SIZE).min(limit.saturating_sub(heap_end));
+    if size < min_size {
+        return None;
+    }
+
+    let mut mapped = 0;
+    interrupts::without_interrupts(|| {
+        let mut mapper = memory::MAPPER.lock();
+        if let Some(mapper) = mapper.as_mut() {
+            // 1ページずつマップし、失敗したらそこで止める
+            // 2MiB境界から2MiB以上残っていればHuge Pageを試す
+            while mapped < size {
+                let start = heap_end + mapped;
+                if start % HUGE_PAGE_SIZE == 0 && size - mapped >= HUGE_PAGE_SIZE {
+                    let page = Page::containing_address(VirtAddr::new(start as u64));
+                    let flags = PageTableFlags::WRITABLE;
+                    if region::map_anonymous_huge(page, flags, mapper, &mut GlobalFrameAllocator)
+                        .is_ok()
+                    {
+                        mapped += HUGE_PAGE_SIZE;
+                        continue;
+                    }
+                }
+                if map_heap_pages(start, PAGE_SIZE, mapper, &mut GlobalFrameAllocator).is_err() {
+                    break;
+                }
+                mapped += PAGE_SIZE;
+            }
+        }
+    });
+    HEAP_END.store(heap_end + mapped, Ordering::Relaxed);
+
+    if mapped == 0 {
+        None
+    } else {
+        Some(mapped)
+    }
 }
 
 // ジェネリクスで他の型でも対応可
@@ -1,4 +1,4 @@
-use super::{align_up, Locked};
+use super::{align_up, grow_heap, stats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -30,26 +30,38 @@ impl BumpAllocator {
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // get mutable reference
-        let mut bump = self.lock();
+        let ptr = {
+            // get mutable reference
+            let mut bump = self.lock();
 
-        let alloc_start = align_up(bump.next, layout.align());
-        let alloc_end = match alloc_start.checked_add(layout.size()) {
-            Some(end) => end,
-            None => return ptr::null_mut(),
-        };
+            let alloc_start = align_up(bump.next, layout.align());
+            let alloc_end = match alloc_start.checked_add(layout.size()) {
+                Some(end) => end,
+                None => return ptr::null_mut(),
+            };
 
-        if alloc_end > bump.heap_end {
-            ptr::null_mut()
-        } else {
-            bump.next = alloc_end;
-            bump.allocations += 1;
-            alloc_start as *mut u8
-        }
+            // 足りなければヒープを拡張する
+            if alloc_end > bump.heap_end {
+                if let Some(size) = grow_heap(bump.heap_end, alloc_end - bump.heap_end) {
+                    bump.heap_end += size;
+                }
+            }
+
+            if alloc_end > bump.heap_end {
+                ptr::null_mut()
+            } else {
+                bump.next = alloc_end;
+                bump.allocations += 1;
+                alloc_start as *mut u8
+            }
+        };
+        stats::record_alloc(ptr, layout);
+        ptr
     }
 
     // allocationsを減らすのみ
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        stats::record_dealloc(ptr, layout);
         // get mutable reference
         let mut bump = self.lock();
 
@@ -1,4 +1,4 @@
-use super::Locked;
+use super::{align_up, grow_heap, stats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{
     mem,
@@ -7,38 +7,22 @@ use core::{
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => match allocator.list_heads[index].take() {
-                Some(node) => {
-                    allocator.list_heads[index] = node.next.take();
-                    node as *mut ListNode as *mut u8
-                }
-                None => {
-                    let block_size = BLOCK_SIZES[index];
-                    let block_align = block_size;
-                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                    allocator.fallback_alloc(layout)
-                }
-            },
-            None => allocator.fallback_alloc(layout),
-        }
+        let ptr = {
+            let mut allocator = self.lock();
+            match list_index(&layout) {
+                Some(index) => allocator.alloc_block(index),
+                None => allocator.fallback_alloc(layout),
+            }
+        };
+        stats::record_alloc(ptr, layout);
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        stats::record_dealloc(ptr, layout);
         let mut allocator = self.lock();
         match list_index(&layout) {
-            Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                // ブロックがノードを格納できるサイズとアラインメントを持っていることを確認
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
+            Some(index) => allocator.dealloc_block(index, ptr),
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
                 allocator.fallback_allocator.deallocate(ptr, layout);
@@ -51,6 +35,54 @@ struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
+// スラブの先頭に置かれるヘッダ
+// スラブは自身のサイズでアラインされているので、ブロックのアドレスの下位bitを落とせば求められる
+struct Slab {
+    // 使用中のブロック数
+    in_use: usize,
+    // スラブに入るブロック数
+    capacity: usize,
+    // 一度解放されたブロックの連結リスト
+    free_list: Option<&'static mut ListNode>,
+    // まだ一度も切り出していない領域の先頭
+    bump: usize,
+    // 空きブロックを持つスラブの双方向連結リスト
+    prev: *mut Slab,
+    next: *mut Slab,
+}
+
+impl Slab {
+    fn is_full(&self) -> bool {
+        self.in_use == self.capacity
+    }
+
+    /// ブロックを一つ取り出す。呼び出し元はスラブが満杯でないことを保証すること。
+    unsafe fn pop_block(&mut self, block_size: usize) -> *mut u8 {
+        let block = match self.free_list.take() {
+            Some(node) => {
+                self.free_list = node.next.take();
+                node as *mut ListNode as *mut u8
+            }
+            None => {
+                let block = self.bump;
+                self.bump += block_size;
+                block as *mut u8
+            }
+        };
+        self.in_use += 1;
+        block
+    }
+
+    unsafe fn push_block(&mut self, ptr: *mut u8) {
+        let node_ptr = ptr as *mut ListNode;
+        node_ptr.write(ListNode {
+            next: self.free_list.take(),
+        });
+        self.free_list = Some(&mut *node_ptr);
+        self.in_use -= 1;
+    }
+}
+
 /// 使用するブロックサイズ。
 ///
 /// これらは2の累乗でなければならない。なぜなら、これらは
@@ -58,17 +90,74 @@ struct ListNode {
 /// 2048バイトより大きければ代替の連結リストアロケータに任せる
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+// 1つのスラブに入れるブロック数の目安
+const BLOCKS_PER_SLAB: usize = 32;
+
+const PAGE_SIZE: usize = 4096;
+
+/// ブロックサイズからスラブのサイズを求める。
+///
+/// 小さいブロックは1ページを切り分け、大きいブロックはヘッダの無駄が
+/// 小さくなるように複数ページをまとめる。常に2の累乗になる。
+const fn slab_size(block_size: usize) -> usize {
+    if block_size * BLOCKS_PER_SLAB > PAGE_SIZE {
+        block_size * BLOCKS_PER_SLAB
+    } else {
+        PAGE_SIZE
+    }
+}
+
+// ヘッダが使う分を除いた最初のブロックの位置
+fn first_block_offset(block_size: usize) -> usize {
+    align_up(mem::size_of::<Slab>(), block_size)
+}
+
+fn slab_capacity(block_size: usize) -> usize {
+    (slab_size(block_size) - first_block_offset(block_size)) / block_size
+}
+
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    // サイズごとの、空きブロックを持つスラブのリスト
+    partial: [*mut Slab; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    // サイズごとの使用中のブロック数とスラブの数
+    live: [usize; BLOCK_SIZES.len()],
+    slabs: [usize; BLOCK_SIZES.len()],
+}
+
+// 生ポインタを持つのでSendが自動では実装されない
+// 常にLockedの中からしか触られないので、スレッド間で移動しても問題ない
+unsafe impl Send for FixedSizeBlockAllocator {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    pub live: usize,
+    pub free: usize,
+    pub slabs: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FixedSizeBlockStats {
+    pub classes: [SizeClassStats; BLOCK_SIZES.len()],
+    pub fallback_used: usize,
+    pub fallback_size: usize,
+}
+
+impl FixedSizeBlockStats {
+    /// スラブの中で空いていて、他のサイズには使えないバイト数を返す。
+    pub fn free_block_bytes(&self) -> usize {
+        self.classes.iter().map(|c| c.block_size * c.free).sum()
+    }
 }
 
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
-        const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            partial: [ptr::null_mut(); BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            live: [0; BLOCK_SIZES.len()],
+            slabs: [0; BLOCK_SIZES.len()],
         }
     }
 
@@ -81,9 +170,151 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    /// サイズごとのブロック数と代替アロケータの使用量を返す。
+    pub fn stats(&self) -> FixedSizeBlockStats {
+        let mut classes = [SizeClassStats {
+            block_size: 0,
+            live: 0,
+            free: 0,
+            slabs: 0,
+        }; BLOCK_SIZES.len()];
+        for (index, class) in classes.iter_mut().enumerate() {
+            let block_size = BLOCK_SIZES[index];
+            class.block_size = block_size;
+            class.live = self.live[index];
+            class.free = self.slabs[index] * slab_capacity(block_size) - self.live[index];
+            class.slabs = self.slabs[index];
+        }
+
+        FixedSizeBlockStats {
+            classes,
+            fallback_used: self.fallback_allocator.used(),
+            fallback_size: self.fallback_allocator.size(),
+        }
+    }
+
+    /// `BLOCK_SIZES[index]`のブロックを一つ割り当てる。
+    ///
+    /// 空きのあるスラブがなければ、代替アロケータから新しいスラブを切り出す。
+    fn alloc_block(&mut self, index: usize) -> *mut u8 {
+        let mut slab = self.partial[index];
+        if slab.is_null() {
+            slab = self.new_slab(index);
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+        }
+
+        unsafe {
+            let block = (*slab).pop_block(BLOCK_SIZES[index]);
+            // 満杯になったスラブはリストから外す
+            if (*slab).is_full() {
+                self.unlink(index, slab);
+            }
+            self.live[index] += 1;
+            block
+        }
+    }
+
+    /// `alloc_block`で割り当てたブロックを解放する。
+    ///
+    /// スラブが空になれば代替アロケータに返す。ただし、割り当てと解放を繰り返したときに
+    /// スラブを作り直さないよう、そのサイズの最後の空きスラブは残しておく。
+    unsafe fn dealloc_block(&mut self, index: usize, ptr: *mut u8) {
+        let block_size = BLOCK_SIZES[index];
+        let slab = (ptr as usize & !(slab_size(block_size) - 1)) as *mut Slab;
+
+        let was_full = (*slab).is_full();
+        (*slab).push_block(ptr);
+        self.live[index] -= 1;
+        if was_full {
+            self.link(index, slab);
+        }
+
+        let only_slab = self.partial[index] == slab && (*slab).next.is_null();
+        if (*slab).in_use == 0 && !only_slab {
+            self.unlink(index, slab);
+            self.release_slab(index, slab);
+        }
+    }
+
+    // 代替アロケータからスラブを確保し、空きスラブのリストにつなぐ
+    fn new_slab(&mut self, index: usize) -> *mut Slab {
+        let block_size = BLOCK_SIZES[index];
+        let size = slab_size(block_size);
+        let layout = Layout::from_size_align(size, size).unwrap();
+
+        let start = self.fallback_alloc(layout);
+        if start.is_null() {
+            return ptr::null_mut();
+        }
+
+        let slab = start as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                in_use: 0,
+                capacity: slab_capacity(block_size),
+                free_list: None,
+                bump: start as usize + first_block_offset(block_size),
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+            });
+            self.link(index, slab);
+        }
+        self.slabs[index] += 1;
+        slab
+    }
+
+    unsafe fn release_slab(&mut self, index: usize, slab: *mut Slab) {
+        let size = slab_size(BLOCK_SIZES[index]);
+        let layout = Layout::from_size_align(size, size).unwrap();
+        self.fallback_allocator
+            .deallocate(NonNull::new_unchecked(slab as *mut u8), layout);
+        self.slabs[index] -= 1;
+    }
+
+    // スラブを空きスラブのリストの先頭につなぐ
+    unsafe fn link(&mut self, index: usize, slab: *mut Slab) {
+        let head = self.partial[index];
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = head;
+        if !head.is_null() {
+            (*head).prev = slab;
+        }
+        self.partial[index] = slab;
+    }
+
+    // スラブを空きスラブのリストから外す
+    unsafe fn unlink(&mut self, index: usize, slab: *mut Slab) {
+        let (prev, next) = ((*slab).prev, (*slab).next);
+        if prev.is_null() {
+            self.partial[index] = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = ptr::null_mut();
+    }
+
     /// 代替アロケータを使って割り当てを行う。
+    ///
+    /// 空きが足りなければヒープを拡張してもう一度試す。
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         // NonNull型はヌルポインタではないことが保証されている生ポインタの抽象化
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // アラインメントのずれを考慮して多めに確保する
+        let heap_end = self.fallback_allocator.top();
+        match grow_heap(heap_end, layout.size() + layout.align()) {
+            Some(size) => unsafe { self.fallback_allocator.extend(size) },
+            None => return ptr::null_mut(),
+        }
+
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
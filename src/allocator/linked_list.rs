@@ -1,30 +1,85 @@
-use super::align_up;
-use super::Locked;
+use super::{align_up, grow_heap, stats, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
+        let ptr = {
+            let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
+            let mut found = allocator.find_region(size, align);
+            if found.is_none() {
+                // 足りなければヒープを拡張してもう一度探す
+                let heap_end = allocator.heap_end;
+                if let Some(grown) = grow_heap(heap_end, size + align) {
+                    allocator.add_free_region(heap_end, grown);
+                    allocator.heap_end += grown;
+                    found = allocator.find_region(size, align);
+                }
             }
-            alloc_start as *mut u8
-        } else {
-            ptr::null_mut()
-        }
+
+            if let Some((region, alloc_start)) = found {
+                let alloc_end = alloc_start.checked_add(size).expect("overflow");
+                let excess_size = region.end_addr() - alloc_end;
+                if excess_size > 0 {
+                    allocator.add_free_region(alloc_end, excess_size);
+                }
+                alloc_start as *mut u8
+            } else {
+                ptr::null_mut()
+            }
+        };
+        stats::record_alloc(ptr, layout);
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        stats::record_dealloc(ptr, layout);
         let (size, _) = LinkedListAllocator::size_align(layout);
 
         self.lock().add_free_region(ptr as usize, size);
     }
+
+    // 直後の領域が空いていれば移動せずにその場で伸ばす
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (old_size, _) = LinkedListAllocator::size_align(layout);
+        let (new_size, _) = LinkedListAllocator::size_align(new_layout);
+        let addr = ptr as usize;
+
+        let in_place = {
+            let mut allocator = self.lock();
+            if new_size == old_size {
+                true
+            } else if new_size < old_size {
+                // 縮める場合は後ろの余りを解放する
+                // 余りがノードを格納できない大きさなら移動する
+                let excess = old_size - new_size;
+                if excess >= mem::size_of::<ListNode>() {
+                    allocator.add_free_region(addr + new_size, excess);
+                    true
+                } else {
+                    false
+                }
+            } else {
+                allocator.take_region_at(addr + old_size, new_size - old_size)
+            }
+        };
+        if in_place {
+            stats::record_dealloc(ptr, layout);
+            stats::record_alloc(ptr, new_layout);
+            return ptr;
+        }
+
+        // その場で変更できなければ新しく割り当ててコピーする
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_layout.size()));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
 }
 
 struct ListNode {
@@ -49,6 +104,8 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    // ヒープの終端 拡張した領域はここから追加される
+    heap_end: usize,
 }
 
 impl LinkedListAllocator {
@@ -56,25 +113,90 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_end: 0,
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_end = heap_start + heap_size;
         self.add_free_region(heap_start, heap_size)
     }
 
-    // 与えられたメモリ領域をリストの先頭に追加する
+    // 与えられたメモリ領域をアドレス順を保つようにリストに追加する
+    // 前後の領域と隣接していれば一つの領域に結合する
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // メモリ領域を指定したnodeを作成して、それを次のnodeにlinkする
-        // take()を実装された変数はdropされずにNoneとなる => Option型
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        // 挿入する位置の直前のノードを探す
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        // headはサイズ0のダミーなので結合の対象にしない
+        if current.size > 0 && current.end_addr() == addr {
+            // 直前の領域を後ろに伸ばす
+            current.size += size;
+            Self::merge_next(current);
+        } else {
+            // メモリ領域を指定したnodeを作成して、それを次のnodeにlinkする
+            // take()を実装された変数はdropされずにNoneとなる => Option型
+            let mut node = ListNode::new(size);
+            node.next = current.next.take();
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            Self::merge_next(&mut *node_ptr);
+            current.next = Some(&mut *node_ptr)
+        }
+    }
+
+    // nodeの終端が次の領域の先頭と一致していれば次の領域を取り込む
+    fn merge_next(node: &mut ListNode) {
+        let end = node.end_addr();
+        if let Some(next) = node.next.as_mut() {
+            if next.start_addr() == end {
+                let (size, rest) = (next.size, next.next.take());
+                node.size += size;
+                node.next = rest;
+            }
+        }
+    }
+
+    // addrから始まる空き領域の先頭sizeバイトをリストから取り除く
+    // そのような領域がなければfalseを返す
+    fn take_region_at(&mut self, addr: usize, size: usize) -> bool {
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        let region = match current.next.as_mut() {
+            Some(region) if region.start_addr() == addr => region,
+            _ => return false,
+        };
+        if region.size < size {
+            return false;
+        }
+        let excess_size = region.size - size;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return false;
+        }
+
+        let next = region.next.take();
+        current.next = next;
+        if excess_size > 0 {
+            unsafe { self.add_free_region(addr + size, excess_size) };
+        }
+        true
     }
 
     // 与えられたサイズの領域の解放された領域を探し、リストからそれを取り除く
@@ -126,3 +248,58 @@ impl LinkedListAllocator {
         (size, layout.align())
     }
 }
+
+#[test_case]
+fn free_regions_are_coalesced() {
+    #[repr(align(16))]
+    struct Arena([u8; 4096]);
+    static mut ARENA: Arena = Arena([0; 4096]);
+
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe {
+        allocator.lock().init(&raw mut ARENA as usize, 4096);
+
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        let blocks = [
+            allocator.alloc(layout),
+            allocator.alloc(layout),
+            allocator.alloc(layout),
+            allocator.alloc(layout),
+        ];
+        assert!(blocks.iter().all(|b| !b.is_null()));
+
+        // 順不同に解放しても一つの領域に戻る
+        for &i in &[1, 3, 0, 2] {
+            allocator.dealloc(blocks[i], layout);
+        }
+        let whole = Layout::from_size_align(4096, 8).unwrap();
+        let ptr = allocator.alloc(whole);
+        assert!(!ptr.is_null());
+        allocator.dealloc(ptr, whole);
+    }
+}
+
+#[test_case]
+fn realloc_grows_in_place() {
+    #[repr(align(16))]
+    struct Arena([u8; 4096]);
+    static mut ARENA: Arena = Arena([0; 4096]);
+
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe {
+        allocator.lock().init(&raw mut ARENA as usize, 4096);
+
+        let layout = Layout::from_size_align(512, 8).unwrap();
+        let ptr = allocator.alloc(layout);
+        ptr.write_bytes(7, 512);
+
+        let grown = allocator.realloc(ptr, layout, 2048);
+        assert_eq!(grown, ptr);
+        assert_eq!(*grown.add(511), 7);
+
+        let layout = Layout::from_size_align(2048, 8).unwrap();
+        let shrunk = allocator.realloc(grown, layout, 256);
+        assert_eq!(shrunk, ptr);
+        allocator.dealloc(shrunk, Layout::from_size_align(256, 8).unwrap());
+    }
+}
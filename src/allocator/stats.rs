@@ -0,0 +1,235 @@
+use crate::backtrace;
+use alloc::alloc::Layout;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// 全アロケータ共通のカウンタ
+// アロケータのロックの外で更新するのでatomicにしている
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCS: AtomicU64 = AtomicU64::new(0);
+static BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+// デバッグモード: 生きている割り当てを記録する
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+// 割り込みハンドラの中の割り当てで止まらないよう、記録表のロックは割り込みを止めてから取る
+fn with_tracker<R>(f: impl FnOnce(&mut Tracker) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut TRACKER.lock()))
+}
+
+// 記録できる割り当ての最大数
+// 記録自体にヒープは使えないので固定長
+const MAX_RECORDS: usize = 256;
+// 割り当てごとに記録する戻り先の数
+// アロケータとallocクレートの中のフレームを越えて、要求した関数まで届く深さにする
+const CALLER_FRAMES: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Counters {
+    pub allocs: u64,
+    pub deallocs: u64,
+    // 要求されたサイズの合計 (ブロックの切り上げ分は含まない)
+    pub bytes_in_use: usize,
+    pub peak_bytes: usize,
+}
+
+/// 起動してからの割り当て回数や使用量を返す。
+pub fn counters() -> Counters {
+    Counters {
+        allocs: ALLOCS.load(Ordering::Relaxed),
+        deallocs: DEALLOCS.load(Ordering::Relaxed),
+        bytes_in_use: BYTES_IN_USE.load(Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+// GlobalAllocの実装から割り当て成功後に呼ばれる
+pub(super) fn record_alloc(ptr: *mut u8, layout: Layout) {
+    if ptr.is_null() {
+        return;
+    }
+
+    ALLOCS.fetch_add(1, Ordering::Relaxed);
+    let in_use = BYTES_IN_USE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+    PEAK_BYTES.fetch_max(in_use, Ordering::Relaxed);
+
+    if TRACKING.load(Ordering::Relaxed) {
+        // どのフレームが要求した関数かは表示するときにシンボルで決める
+        let mut callers = [0; CALLER_FRAMES];
+        backtrace::walk(backtrace::current_frame_pointer(), &mut callers);
+        with_tracker(|tracker| tracker.insert(ptr as usize, layout.size(), callers));
+    }
+}
+
+// GlobalAllocの実装から解放前に呼ばれる
+pub(super) fn record_dealloc(ptr: *mut u8, layout: Layout) {
+    DEALLOCS.fetch_add(1, Ordering::Relaxed);
+    BYTES_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+
+    if TRACKING.load(Ordering::Relaxed) {
+        with_tracker(|tracker| tracker.remove(ptr as usize));
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AllocRecord {
+    pub addr: usize,
+    pub size: usize,
+    // 割り当てられた順番
+    pub seq: u64,
+    // 割り当て時に有効だった`alloc_site!`の位置
+    pub site: Option<&'static str>,
+    // record_allocからたどった戻り先のアドレス (新しい順、残りは0)
+    callers: [u64; CALLER_FRAMES],
+}
+
+impl AllocRecord {
+    /// 割り当てを要求した関数の中の戻り先のアドレスを返す。
+    ///
+    /// アロケータ、allocクレートとcoreクレートの中のフレームは飛ばす。
+    /// シンボル表が埋め込まれていなければ見分けられないので`None`を返す。
+    pub fn caller(&self) -> Option<u64> {
+        self.callers
+            .iter()
+            .copied()
+            .take_while(|&address| address != 0)
+            .find(|&address| match backtrace::symbolize(address) {
+                Some((name, _)) => !is_allocator_frame(name),
+                None => false,
+            })
+    }
+}
+
+fn is_allocator_frame(name: &str) -> bool {
+    // "<alloc::vec::Vec<T> as ...>::clone"のようなトレイトの実装も含める
+    let name = name.trim_start_matches('<');
+    [
+        "jura_os::allocator::",
+        "alloc::",
+        "core::",
+        "__rust_",
+        "__rg_",
+        "__rdl_",
+    ]
+    .iter()
+    .any(|prefix| name.starts_with(prefix))
+}
+
+struct Tracker {
+    records: [Option<AllocRecord>; MAX_RECORDS],
+    site: Option<&'static str>,
+    next_seq: u64,
+    // 表がいっぱいで記録できなかった数
+    dropped: usize,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        Tracker {
+            records: [None; MAX_RECORDS],
+            site: None,
+            next_seq: 0,
+            dropped: 0,
+        }
+    }
+
+    fn insert(&mut self, addr: usize, size: usize, callers: [u64; CALLER_FRAMES]) {
+        let record = AllocRecord {
+            addr,
+            size,
+            seq: self.next_seq,
+            site: self.site,
+            callers,
+        };
+        self.next_seq += 1;
+
+        match self.records.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => *slot = Some(record),
+            None => self.dropped += 1,
+        }
+    }
+
+    fn remove(&mut self, addr: usize) {
+        if let Some(slot) = self
+            .records
+            .iter_mut()
+            .find(|r| matches!(r, Some(record) if record.addr == addr))
+        {
+            *slot = None;
+        }
+    }
+}
+
+/// 割り当ての記録を有効または無効にする。
+///
+/// 無効にしたときは記録を全て消す。
+pub fn set_tracking(enabled: bool) {
+    with_tracker(|tracker| {
+        if !enabled {
+            tracker.records = [None; MAX_RECORDS];
+            tracker.dropped = 0;
+        }
+        TRACKING.store(enabled, Ordering::Relaxed);
+    });
+}
+
+pub fn is_tracking() -> bool {
+    TRACKING.load(Ordering::Relaxed)
+}
+
+/// 次に記録される割り当ての番号を返す。
+///
+/// `for_each_live`に渡すと、これ以降の割り当てのうち解放されていないものだけを調べられる。
+pub fn mark() -> u64 {
+    with_tracker(|tracker| tracker.next_seq)
+}
+
+/// 番号が`since`以上で、まだ解放されていない割り当てを順に渡す。
+///
+/// 1件ずつ写してからロックを外して渡すので、`f`の中でヒープを使ったり出力したりしてもよい。
+/// 表がいっぱいで記録できなかった数を返す。
+pub fn for_each_live<F>(since: u64, mut f: F) -> usize
+where
+    F: FnMut(&AllocRecord),
+{
+    for i in 0..MAX_RECORDS {
+        if let Some(record) = with_tracker(|tracker| tracker.records[i]) {
+            if record.seq >= since {
+                f(&record);
+            }
+        }
+    }
+    with_tracker(|tracker| tracker.dropped)
+}
+
+// `alloc_site!`が返すガード
+// dropされると一つ前の位置に戻す
+pub struct SiteGuard {
+    previous: Option<&'static str>,
+}
+
+impl Drop for SiteGuard {
+    fn drop(&mut self) {
+        let previous = self.previous;
+        with_tracker(|tracker| tracker.site = previous);
+    }
+}
+
+/// 以降の割り当てを`site`からのものとして記録する。
+///
+/// 通常は`alloc_site!`マクロを使う。
+pub fn enter_site(site: &'static str) -> SiteGuard {
+    let previous = with_tracker(|tracker| tracker.site.replace(site));
+    SiteGuard { previous }
+}
+
+// ガードが生きている間の割り当てをこのファイルと行番号で記録する
+#[macro_export]
+macro_rules! alloc_site {
+    () => {
+        $crate::allocator::stats::enter_site(concat!(file!(), ":", line!()))
+    };
+}
@@ -0,0 +1,332 @@
+// Local APICとIO-APIC
+// 8259 PICを無効にし、キーボードはIO-APICから、タイマはLocal APICのタイマから割り込みを受ける
+// 割り込みベクタはPICのときと同じ`InterruptIndex`を使う
+
+use crate::acpi::{self, Polarity, TriggerMode};
+use crate::interrupts::{irq, InterruptIndex, PIC_1_OFFSET};
+use crate::memory::vmalloc::{self, VmRegion};
+use crate::time;
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::PhysAddr;
+
+// 割り込みとして扱わなくてよい、偽の割り込みのベクタ
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// IO-APICの既定の物理アドレス
+pub const IO_APIC_DEFAULT_BASE: u64 = 0xfec0_0000;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+// IA32_APIC_BASEのAPICを有効にするbit
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+// IA32_APIC_BASEの物理アドレスのbit (bit12からMAXPHYADDRまで、最大52bit)
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Local APICのレジスタのオフセット
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+// 処理中の割り込みのbit (32ベクタごとに0x10ずつ並ぶ)
+const LAPIC_ISR: usize = 0x100;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+// SVRのAPICを有効にするbit
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+// LVTの割り込みを止めるbit
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+// タイマの入力を16分周する
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// タイマの較正に使うPITの割り込みの回数
+const CALIBRATION_TICKS: u64 = 10;
+
+// IO-APICのレジスタ
+const IO_APIC_VERSION: u32 = 0x01;
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+// リダイレクションエントリの極性、トリガモード、割り込みを止めるbit
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+// Local APICのレジスタがマップされている仮想アドレス
+// EOIは割り込みハンドラから書くので、ロックを取らずに使えるようにしておく
+static LOCAL_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// `init`で設定したIO-APIC
+pub static IO_APIC: Mutex<Option<IoApic>> = Mutex::new(None);
+
+/// CPUがLocal APICを持っているかを返す。
+pub fn is_supported() -> bool {
+    #[allow(unused_unsafe)]
+    let result = unsafe { __cpuid(1) };
+    result.edx & (1 << 9) != 0
+}
+
+/// 割り込みがAPICから届くようになっているかを返す。
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// 8259 PICからAPICに切り替える。
+///
+/// Local APICのタイマはPITで較正し、PITと同じ`time::TIMER_HZ`の周期で
+/// `InterruptIndex::Timer`を発生させる。ハンドラが登録されているISAの割り込みは、
+/// PICのときと同じベクタにIO-APICで送る。
+/// APICのアドレスとISAの割り込みの繋がり方は、`acpi::init`が済んでいればMADTに従う。
+/// `memory::vmalloc::init`の後、割り込みが有効な状態で呼び出すこと。
+/// APICがない場合は何もせず、PICを使い続ける。
+pub fn init() {
+    if !is_supported() || is_enabled() {
+        return;
+    }
+
+    let local_apic = unsafe { map_registers(PhysAddr::new(local_apic_base())) };
+    LOCAL_APIC_BASE.store(local_apic.start().as_u64(), Ordering::Release);
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        let value = msr.read();
+        msr.write(value | APIC_GLOBAL_ENABLE);
+        write_local(
+            LAPIC_SPURIOUS,
+            SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+    }
+
+    let ticks_per_interrupt = calibrate_timer();
+
+    let io_apic_address = acpi::info()
+        .and_then(|info| info.io_apic_for(0))
+        .map_or(IO_APIC_DEFAULT_BASE, |io_apic| io_apic.address);
+    let io_apic = unsafe { IoApic::new(PhysAddr::new(io_apic_address)) };
+    interrupts::without_interrupts(|| {
+        disable_pic();
+
+        let mut io_apic = io_apic;
+        let destination = local_apic_id();
+        for gsi in 0..io_apic.redirection_entries() {
+            io_apic.mask(gsi);
+        }
+        // タイマ割り込みはLocal APICのタイマが発生させるので、PITの割り込みは止めたままにする
+        for irq in (0..irq::ISA_IRQ_COUNT).filter(|&irq| irq::is_requested(irq)) {
+            route_isa_irq(&mut io_apic, irq, destination);
+        }
+        *IO_APIC.lock() = Some(io_apic);
+
+        unsafe {
+            write_local(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            write_local(
+                LAPIC_LVT_TIMER,
+                LVT_TIMER_PERIODIC | u32::from(InterruptIndex::Timer.as_u8()),
+            );
+            write_local(LAPIC_TIMER_INITIAL_COUNT, ticks_per_interrupt);
+        }
+        ENABLED.store(true, Ordering::Release);
+    });
+}
+
+/// ISAの`irq`の割り込みをIO-APICで`PIC_1_OFFSET + irq`のベクタに送る。
+///
+/// APICを使っていなければ何もしない。PITの割り込み (IRQ 0) はLocal APICのタイマに
+/// 置き換えているので送らない。
+pub fn enable_isa_irq(irq: u8) {
+    if !is_enabled() {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        if let Some(io_apic) = IO_APIC.lock().as_mut() {
+            route_isa_irq(io_apic, irq, local_apic_id());
+        }
+    });
+}
+
+/// ISAの`irq`の割り込みを止める。APICを使っていなければ何もしない。
+pub fn disable_isa_irq(irq: u8) {
+    if !is_enabled() {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        if let Some(io_apic) = IO_APIC.lock().as_mut() {
+            io_apic.mask(isa_route(irq).0);
+        }
+    });
+}
+
+fn route_isa_irq(io_apic: &mut IoApic, irq: u8, destination: u8) {
+    if irq == InterruptIndex::Timer.isa_irq() {
+        return;
+    }
+    let (gsi, polarity, trigger) = isa_route(irq);
+    io_apic.route(gsi, PIC_1_OFFSET + irq, destination);
+    io_apic.set_mode(gsi, polarity, trigger);
+}
+
+/// Local APICに割り込みの処理が終わったことを伝える。
+pub fn end_of_interrupt() {
+    unsafe { write_local(LAPIC_EOI, 0) };
+}
+
+/// `vector`の割り込みをLocal APICが処理中か (ISRのbitが立っているか) を返す。
+///
+/// ソフトウェアの`int`で起きた割り込みでは立たない。
+pub fn is_in_service(vector: u8) -> bool {
+    let offset = LAPIC_ISR + 0x10 * usize::from(vector / 32);
+    unsafe { read_local(offset) & 1 << (vector % 32) != 0 }
+}
+
+/// このCPUのLocal APIC IDを返す。
+pub fn local_apic_id() -> u8 {
+    (unsafe { read_local(LAPIC_ID) } >> 24) as u8
+}
+
+// ISAの`irq`が繋がっているGSIと、その極性、トリガモード
+// MADTにオーバーライドがなければ同じ番号のGSIに繋がっている
+fn isa_route(irq: u8) -> (u32, Polarity, TriggerMode) {
+    match acpi::info().and_then(|info| info.isa_override(irq)) {
+        Some(entry) => (entry.gsi, entry.polarity, entry.trigger),
+        None => (irq.into(), Polarity::Conforming, TriggerMode::Conforming),
+    }
+}
+
+// PITの割り込みの間にLocal APICのタイマが何回数えるかを測る
+// この時点ではまだPICからPITの割り込みが届いている必要がある
+fn calibrate_timer() -> u32 {
+    unsafe {
+        write_local(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write_local(LAPIC_LVT_TIMER, LVT_MASKED);
+    }
+
+    // PITの割り込みの境目から数え始める
+    let start = time::ticks();
+    while time::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    unsafe { write_local(LAPIC_TIMER_INITIAL_COUNT, u32::MAX) };
+    let start = time::ticks();
+    while time::ticks() < start + CALIBRATION_TICKS {
+        x86_64::instructions::hlt();
+    }
+    let remaining = unsafe { read_local(LAPIC_TIMER_CURRENT_COUNT) };
+    unsafe { write_local(LAPIC_TIMER_INITIAL_COUNT, 0) };
+
+    ((u32::MAX - remaining) as u64 / CALIBRATION_TICKS) as u32
+}
+
+// 8259 PICの全ての割り込みを止める
+// 割り込みベクタはinitializeで例外と重ならない位置に移してあるので、偽の割り込みが来ても問題ない
+fn disable_pic() {
+    let mut pic_1_data: Port<u8> = Port::new(0x21);
+    let mut pic_2_data: Port<u8> = Port::new(0xa1);
+    unsafe {
+        pic_1_data.write(0xff);
+        pic_2_data.write(0xff);
+    }
+}
+
+// Local APICのレジスタの物理アドレス
+// `acpi::init`が済んでいればMADTの値 (64bitのアドレスの上書きを含む) を使い、
+// なければIA32_APIC_BASEのbit12以上を使う
+fn local_apic_base() -> u64 {
+    match acpi::info().map(|info| info.local_apic_address) {
+        Some(address) if address != 0 => address,
+        _ => unsafe { Msr::new(IA32_APIC_BASE).read() & APIC_BASE_ADDRESS_MASK },
+    }
+}
+
+// この関数はunsafeである：physにデバイスのレジスタがなければならない
+unsafe fn map_registers(phys: PhysAddr) -> VmRegion {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    vmalloc::map_physical(phys, 4096, flags).expect("failed to map APIC registers")
+}
+
+unsafe fn read_local(offset: usize) -> u32 {
+    let base = LOCAL_APIC_BASE.load(Ordering::Acquire) as usize;
+    ptr::read_volatile((base + offset) as *const u32)
+}
+
+unsafe fn write_local(offset: usize, value: u32) {
+    let base = LOCAL_APIC_BASE.load(Ordering::Acquire) as usize;
+    ptr::write_volatile((base + offset) as *mut u32, value);
+}
+
+// IO-APIC
+// レジスタはIOREGSELに番号を書いてからIOWINで読み書きする
+pub struct IoApic {
+    registers: VmRegion,
+}
+
+impl IoApic {
+    /// `phys`にあるIO-APICのレジスタをマップする。
+    ///
+    /// この関数はunsafeである：呼び出し元は`phys`がIO-APICのアドレスであることを
+    /// 保証しなければならない。
+    pub unsafe fn new(phys: PhysAddr) -> Self {
+        IoApic {
+            registers: map_registers(phys),
+        }
+    }
+
+    /// リダイレクションテーブルのエントリ数 (扱えるGSIの数) を返す。
+    pub fn redirection_entries(&mut self) -> u32 {
+        ((self.read(IO_APIC_VERSION) >> 16) & 0xff) + 1
+    }
+
+    /// `gsi`の割り込みを`destination`のLocal APICに`vector`として送る。
+    ///
+    /// ISAの割り込みと同じく、エッジトリガ、アクティブハイとして設定する。
+    pub fn route(&mut self, gsi: u32, vector: u8, destination: u8) {
+        let register = IO_APIC_REDIRECTION_TABLE + gsi * 2;
+        unsafe {
+            self.write(register + 1, u32::from(destination) << 24);
+            self.write(register, u32::from(vector));
+        }
+    }
+
+    /// `gsi`の極性とトリガモードを設定する。`Conforming`はISAの規定 (アクティブハイ、エッジ) とする。
+    pub fn set_mode(&mut self, gsi: u32, polarity: Polarity, trigger: TriggerMode) {
+        let register = IO_APIC_REDIRECTION_TABLE + gsi * 2;
+        unsafe {
+            let mut low = self.read(register) & !(REDIRECTION_ACTIVE_LOW | REDIRECTION_LEVEL);
+            if polarity == Polarity::ActiveLow {
+                low |= REDIRECTION_ACTIVE_LOW;
+            }
+            if trigger == TriggerMode::Level {
+                low |= REDIRECTION_LEVEL;
+            }
+            self.write(register, low);
+        }
+    }
+
+    /// `gsi`の割り込みを止める。
+    pub fn mask(&mut self, gsi: u32) {
+        let register = IO_APIC_REDIRECTION_TABLE + gsi * 2;
+        unsafe {
+            let low = self.read(register);
+            self.write(register, low | REDIRECTION_MASKED);
+        }
+    }
+
+    fn read(&mut self, register: u32) -> u32 {
+        unsafe {
+            let base = self.registers.as_mut_ptr::<u32>();
+            ptr::write_volatile(base, register);
+            ptr::read_volatile(base.add(4))
+        }
+    }
+
+    unsafe fn write(&mut self, register: u32, value: u32) {
+        let base = self.registers.as_mut_ptr::<u32>();
+        ptr::write_volatile(base, register);
+        ptr::write_volatile(base.add(4), value);
+    }
+}
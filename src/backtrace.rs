@@ -0,0 +1,185 @@
+// フレームポインタをたどるスタックの巻き戻し
+// ターゲットの設定 (frame-pointer = always) で全ての関数がrbpにフレームを積むので、
+// [rbp]に呼び出し元のrbp、[rbp + 8]に戻り先のアドレスがある
+//
+// 関数名は`scripts/embed_symbols.sh`がリンク後に`.ksyms`セクションへ書き込んだシンボル表から引く
+
+use crate::memory;
+use core::arch::asm;
+use core::{fmt, ptr, slice, str};
+use x86_64::VirtAddr;
+
+// 記録するフレームの数
+const MAX_FRAMES: usize = 32;
+
+// 一つのスタックがこれより大きく離れたフレームを指していれば、壊れているとみなす
+const MAX_FRAME_DISTANCE: u64 = 1024 * 1024;
+
+/// シンボル表の大きさ。`scripts/embed_symbols.sh`の`SIZE`と揃えること。
+pub const SYMBOL_TABLE_SIZE: usize = 512 * 1024;
+
+// "<16桁のアドレス> <関数名>\n"がアドレス順に並び、残りは0で埋められる
+// 書き込みはリンク後に行われるので、コンパイラに中身を決めつけられないようstatic mutで外部に公開する
+#[no_mangle]
+#[used]
+#[link_section = ".ksyms"]
+pub static mut KERNEL_SYMBOLS: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+/// 戻り先のアドレスの列
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// 呼び出した関数からのバックトレースを記録する。
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Backtrace::from_frame_pointer(current_frame_pointer())
+    }
+
+    /// `rbp`のフレームから呼び出し元をたどる。
+    ///
+    /// 例外の場合は、例外が起きた関数のrbpを渡す。
+    pub fn from_frame_pointer(rbp: u64) -> Self {
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+        backtrace.len = walk(rbp, &mut backtrace.frames);
+        backtrace
+    }
+
+    /// 戻り先のアドレスを新しいフレームから順に返す。
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+        for (i, &address) in self.frames().iter().enumerate() {
+            write!(f, "  #{:<2} ", i)?;
+            write_address(f, address)?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// `address`を"0x... 関数名+0x..."の形で書き出す。
+pub fn write_address(f: &mut dyn fmt::Write, address: u64) -> fmt::Result {
+    match symbolize(address) {
+        Some((name, offset)) => write!(f, "{:#018x} {}+{:#x}", address, name, offset),
+        None => write!(f, "{:#018x} ?", address),
+    }
+}
+
+/// `rbp`のフレームから呼び出し元をたどり、戻り先のアドレスを新しい順に`frames`へ書く。
+///
+/// 書いたアドレスの数を返す。`Backtrace`より浅くてよい場合に使う。
+pub fn walk(rbp: u64, frames: &mut [u64]) -> usize {
+    let mut len = 0;
+    let mut rbp = rbp;
+    while len < frames.len() && is_readable(rbp) && is_readable(rbp + 8) {
+        let (caller_rbp, return_address) = unsafe {
+            (
+                ptr::read(rbp as *const u64),
+                ptr::read((rbp + 8) as *const u64),
+            )
+        };
+        if return_address == 0 {
+            break;
+        }
+        frames[len] = return_address;
+        len += 1;
+
+        // スタックは下に伸びるので、呼び出し元のフレームは必ず上にある
+        if caller_rbp <= rbp || caller_rbp - rbp > MAX_FRAME_DISTANCE {
+            break;
+        }
+        rbp = caller_rbp;
+    }
+    len
+}
+
+/// 現在の関数のフレームポインタを返す。
+#[inline(always)]
+pub fn current_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// `address`を含む関数の名前と、関数の先頭からのオフセットを返す。
+///
+/// シンボル表が埋め込まれていなければ`None`を返す。
+pub fn symbolize(address: u64) -> Option<(&'static str, u64)> {
+    let mut found = None;
+    for line in symbol_table().split(|&byte| byte == b'\n') {
+        let (start, name) = match parse_symbol(line) {
+            Some(symbol) => symbol,
+            None => continue,
+        };
+        // アドレス順に並んでいるので、越えたら終わり
+        if start > address {
+            break;
+        }
+        found = Some((name, address - start));
+    }
+    found
+}
+
+/// シンボル表が埋め込まれているかを返す。
+pub fn has_symbols() -> bool {
+    !symbol_table().is_empty()
+}
+
+fn symbol_table() -> &'static [u8] {
+    let table = unsafe {
+        let base = &raw const KERNEL_SYMBOLS as *const u8;
+        // 書き込まれていなければ先頭は0のまま
+        if ptr::read_volatile(base) == 0 {
+            return &[];
+        }
+        slice::from_raw_parts(base, SYMBOL_TABLE_SIZE)
+    };
+    let len = table
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(table.len());
+    &table[..len]
+}
+
+fn parse_symbol(line: &[u8]) -> Option<(u64, &str)> {
+    let line = str::from_utf8(line).ok()?;
+    let mut parts = line.splitn(2, ' ');
+    let address = u64::from_str_radix(parts.next()?, 16).ok()?;
+    Some((address, parts.next()?))
+}
+
+// フレームを読んでもページフォルトにならないか
+fn is_readable(addr: u64) -> bool {
+    if addr == 0 || addr % 8 != 0 || VirtAddr::try_new(addr).is_err() {
+        return false;
+    }
+    // メモリ管理の初期化前はページテーブルをたどれないので、上の確認だけで読む
+    let offset = memory::physical_memory_offset();
+    offset.as_u64() == 0 || unsafe { memory::translate_addr(VirtAddr::new(addr), offset) }.is_some()
+}
+
+#[test_case]
+fn backtrace_reaches_caller() {
+    #[inline(never)]
+    fn inner() -> Backtrace {
+        Backtrace::capture()
+    }
+
+    let backtrace = inner();
+    assert!(backtrace.frames().len() >= 2);
+    // 最初の戻り先は、この関数の中にある
+    let here = backtrace_reaches_caller as usize as u64;
+    assert!(backtrace.frames()[0] > here);
+}
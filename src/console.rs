@@ -0,0 +1,247 @@
+// 画面とシリアルポートをまとめたコンソール
+// print!/println!の出力は登録された全ての出力先 (Sink) に書き出される
+// 入力はキーボードとシリアルポートのどちらからでも受け付け、入力元の出力先にシェルの結果を返す
+//
+// 割り込みハンドラの中からの出力は`deferred`に溜め、タスクの側で出力する
+// panicや回復できない例外では`enter_panic_mode`でロックを外し、直接書き出す
+
+use crate::{interrupts, serial, vga_buffer};
+use core::fmt::{self, Write};
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub mod deferred;
+pub mod input;
+pub mod ring_buffer;
+
+pub use ring_buffer::RingBuffer;
+
+// 同時に登録できる出力先の数
+const MAX_SINKS: usize = 8;
+
+/// 直近の出力を覚えておくバッファの大きさ
+pub const HISTORY_SIZE: usize = 16 * 1024;
+
+/// コンソールの出力先
+pub trait Sink: Sync {
+    /// `console::remove_sink`などで出力先を区別するための名前
+    fn name(&self) -> &'static str;
+
+    fn write_str(&self, s: &str);
+
+    /// 画面を消す。消せない出力先では何もしない。
+    fn clear(&self) {}
+
+    /// 出力先が持っているロックを強制的に外す。
+    ///
+    /// # Safety
+    /// ロックを持っていた側には二度と戻らない場合 (panicなど) にだけ呼び出すこと。
+    unsafe fn force_unlock(&self) {}
+}
+
+/// VGAのテキストバッファ
+pub struct VgaSink;
+
+impl Sink for VgaSink {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn write_str(&self, s: &str) {
+        vga_buffer::_print(format_args!("{}", s));
+    }
+
+    fn clear(&self) {
+        without_interrupts(|| {
+            let mut writer = vga_buffer::WRITER.lock();
+            // 書き込まれている行を全て上に流す
+            for _ in 0..writer.row_position + 1 {
+                writer.write_byte(b'\n');
+            }
+        });
+    }
+
+    unsafe fn force_unlock(&self) {
+        vga_buffer::WRITER.force_unlock();
+    }
+}
+
+/// COM1
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn write_str(&self, s: &str) {
+        let _ = serial::SerialWriter.write_str(s);
+    }
+
+    fn clear(&self) {
+        // ANSIのエスケープシーケンスで画面を消し、カーソルを左上に戻す
+        serial::_print(format_args!("\x1b[2J\x1b[H"));
+    }
+
+    unsafe fn force_unlock(&self) {
+        serial::SERIAL1.force_unlock();
+    }
+}
+
+pub static VGA: VgaSink = VgaSink;
+pub static SERIAL: SerialSink = SerialSink;
+/// 直近の出力。シェルやテストから読み返せる。
+pub static HISTORY: RingBuffer<HISTORY_SIZE> = RingBuffer::new("history");
+
+/// 出力先を登録する際のエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    /// 同じ名前の出力先が既にある
+    AlreadyAdded,
+    /// これ以上登録できない
+    TooManySinks,
+}
+
+// 起動直後から画面とシリアルポートの両方に出力する
+static SINKS: Mutex<[Option<&'static dyn Sink>; MAX_SINKS]> = Mutex::new([
+    Some(&VGA),
+    Some(&SERIAL),
+    Some(&HISTORY),
+    None,
+    None,
+    None,
+    None,
+    None,
+]);
+
+// panicの処理中か
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// 出力先を追加する。
+pub fn add_sink(sink: &'static dyn Sink) -> Result<(), ConsoleError> {
+    without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        if sinks.iter().flatten().any(|s| s.name() == sink.name()) {
+            return Err(ConsoleError::AlreadyAdded);
+        }
+        let slot = sinks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ConsoleError::TooManySinks)?;
+        *slot = Some(sink);
+        Ok(())
+    })
+}
+
+/// `name`の出力先を外す。登録されていなければ`false`を返す。
+pub fn remove_sink(name: &str) -> bool {
+    without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        match sinks
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |s| s.name() == name))
+        {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// 登録されている出力先を順に`f`に渡す。
+pub fn for_each_sink(mut f: impl FnMut(&'static dyn Sink)) {
+    for sink in sinks().iter().flatten() {
+        f(*sink);
+    }
+}
+
+// 書き込み中に出力先の登録を変えられるよう、ロックを外してから使う
+fn sinks() -> [Option<&'static dyn Sink>; MAX_SINKS] {
+    without_interrupts(|| *SINKS.lock())
+}
+
+/// panicや回復できない例外の報告のために、コンソールのロックを全て外す。
+///
+/// 以降の出力は割り込みハンドラの中からでも溜めずに直接書き出す。
+/// 割り込みハンドラに溜まっていた出力は先に書き出す。
+///
+/// # Safety
+/// 呼び出した後、ロックを持っていた処理に戻ってはいけない。
+pub unsafe fn enter_panic_mode() {
+    PANICKING.store(true, Ordering::SeqCst);
+    SINKS.force_unlock();
+    for sink in sinks().iter().flatten() {
+        sink.force_unlock();
+    }
+    // serial_print!はコンソールを通らないので、登録されていなくても外す
+    serial::SERIAL1.force_unlock();
+    flush_deferred();
+}
+
+/// 割り込みハンドラの中から出力され、溜まっているものを書き出す。
+pub fn flush_deferred() {
+    if deferred::is_empty() {
+        return;
+    }
+    without_interrupts(|| deferred::flush(write_all));
+}
+
+// 登録されている全ての出力先に書き出す
+fn write_all(s: &str) {
+    for sink in sinks().iter().flatten() {
+        sink.write_str(s);
+    }
+}
+
+// 文字の途中で切り捨てていれば、その文字の手前までを返す
+pub(crate) fn utf8_prefix(bytes: &[u8]) -> &str {
+    match str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(err) => str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+/// 一つの出力先だけに書き出す`fmt::Write`
+///
+/// シェルの結果を入力元の端末に返すときに使う。
+#[derive(Clone, Copy)]
+pub struct SinkWriter(pub &'static dyn Sink);
+
+impl fmt::Write for SinkWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    // 割り込まれた側が出力先のロックを持っているかもしれないので、待たずに溜める
+    if interrupts::in_interrupt() && !PANICKING.load(Ordering::SeqCst) {
+        deferred::push(args);
+        return;
+    }
+    flush_deferred();
+    without_interrupts(|| {
+        for sink in sinks().iter().flatten() {
+            let _ = SinkWriter(*sink).write_fmt(args);
+        }
+    });
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => {
+        ($crate::print!("{}\n",format_args!($($arg)*)));
+    }
+}
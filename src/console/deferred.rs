@@ -0,0 +1,150 @@
+// 割り込みハンドラからの出力を溜めておく、ロックを使わないCPUごとのバッファ
+// ハンドラの中でコンソールのロックを待つと、割り込まれた側がロックを持っていれば止まってしまうので、
+// ここに書いておき、タスクの側 (次のprint!かExecutorが休む前) でまとめて出力する
+
+use super::utf8_prefix;
+use crate::apic;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+const MAX_CPUS: usize = 8;
+// CPUごとに溜められるメッセージの数と、1件の長さ。越えた分は切り捨てる
+const SLOTS: usize = 16;
+const MESSAGE_LEN: usize = 256;
+
+// スロットの状態
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+struct Slot {
+    state: AtomicU8,
+    // stateがWRITINGの間は書き込んだ側だけが、READYの間は出力する側だけが触る
+    message: UnsafeCell<Message>,
+}
+
+unsafe impl Sync for Slot {}
+
+#[derive(Clone, Copy)]
+struct Message {
+    seq: u64,
+    len: usize,
+    buf: [u8; MESSAGE_LEN],
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(MESSAGE_LEN - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+const EMPTY_SLOT: Slot = Slot {
+    state: AtomicU8::new(EMPTY),
+    message: UnsafeCell::new(Message {
+        seq: 0,
+        len: 0,
+        buf: [0; MESSAGE_LEN],
+    }),
+};
+const EMPTY_CPU: [Slot; SLOTS] = [EMPTY_SLOT; SLOTS];
+
+static BUFFERS: [[Slot; SLOTS]; MAX_CPUS] = [EMPTY_CPU; MAX_CPUS];
+// 出力の順序を保つための通し番号
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+// 空きがなくて捨てたメッセージの数
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+// 出力するのは一度に一箇所だけ
+static FLUSHING: AtomicBool = AtomicBool::new(false);
+
+/// メッセージを実行中のCPUのバッファに書く。空きがなければ捨てる。
+pub fn push(args: fmt::Arguments) {
+    let slots = &BUFFERS[cpu_index()];
+    // 同じCPUでもNMIなどで入れ子になるので、スロットは奪い合って取る
+    let slot = match slots.iter().find(|slot| {
+        slot.state
+            .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }) {
+        Some(slot) => slot,
+        None => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+
+    let message = unsafe { &mut *slot.message.get() };
+    message.seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+    message.len = 0;
+    let _ = message.write_fmt(args);
+    slot.state.store(READY, Ordering::Release);
+}
+
+/// 溜まっているメッセージを古い順に`write`へ渡す。
+///
+/// 他で出力中なら何もしない。
+pub fn flush(mut write: impl FnMut(&str)) {
+    if FLUSHING
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+
+    while let Some(message) = take_oldest() {
+        write(utf8_prefix(&message.buf[..message.len]));
+    }
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        let mut message = Message {
+            seq: 0,
+            len: 0,
+            buf: [0; MESSAGE_LEN],
+        };
+        let _ = writeln!(
+            message,
+            "({} messages from interrupt handlers dropped)",
+            dropped
+        );
+        write(utf8_prefix(&message.buf[..message.len]));
+    }
+
+    FLUSHING.store(false, Ordering::Release);
+}
+
+/// 出力されていないメッセージがなければ`true`を返す。
+pub fn is_empty() -> bool {
+    BUFFERS
+        .iter()
+        .flatten()
+        .all(|slot| slot.state.load(Ordering::Acquire) != READY)
+        && DROPPED.load(Ordering::Relaxed) == 0
+}
+
+// 全てのCPUのバッファから最も古いメッセージを取り出す
+fn take_oldest() -> Option<Message> {
+    let mut oldest: Option<(&Slot, Message)> = None;
+    for slot in BUFFERS.iter().flatten() {
+        if slot.state.load(Ordering::Acquire) != READY {
+            continue;
+        }
+        let message = unsafe { *slot.message.get() };
+        if oldest.map_or(true, |(_, old)| message.seq < old.seq) {
+            oldest = Some((slot, message));
+        }
+    }
+    let (slot, message) = oldest?;
+    slot.state.store(EMPTY, Ordering::Release);
+    Some(message)
+}
+
+fn cpu_index() -> usize {
+    if apic::is_enabled() {
+        usize::from(apic::local_apic_id()) % MAX_CPUS
+    } else {
+        0
+    }
+}
@@ -0,0 +1,142 @@
+// キーボードとシリアルポートからの入力を1行ずつまとめ、シェルで実行する
+
+use super::{Sink, SinkWriter, SERIAL, VGA};
+use crate::task::{keyboard::ScancodeStream, serial::SerialStream};
+use crate::{exit_qemu, shell, QemuExitCode};
+use alloc::string::String;
+use core::fmt::Write;
+use futures_util::StreamExt;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+
+static PROMPT: &str = "?e235718?jura_os ";
+
+// 入力される制御文字
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+const CTRL_C: char = '\u{3}';
+const CTRL_L: char = '\u{c}';
+
+/// コンソールの入力元
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Keyboard,
+    Serial,
+}
+
+impl Source {
+    /// 入力元と同じ端末の出力先を返す。エコーやシェルの結果はここに書き出す。
+    pub fn sink(self) -> &'static dyn Sink {
+        match self {
+            Source::Keyboard => &VGA,
+            Source::Serial => &SERIAL,
+        }
+    }
+}
+
+// 入力元ごとのストリームを文字の列に変える
+enum Reader {
+    Keyboard {
+        scancodes: ScancodeStream,
+        keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    },
+    Serial {
+        bytes: SerialStream,
+        last_cr: bool,
+    },
+}
+
+impl Reader {
+    fn new(source: Source) -> Self {
+        match source {
+            Source::Keyboard => Reader::Keyboard {
+                scancodes: ScancodeStream::new(),
+                // Ctrl+英字を制御文字 (Ctrl+Cなら'\u{3}') にする
+                keyboard: Keyboard::new(
+                    ScancodeSet1::new(),
+                    layouts::Us104Key,
+                    HandleControl::MapLettersToUnicode,
+                ),
+            },
+            Source::Serial => Reader::Serial {
+                bytes: SerialStream::new(),
+                last_cr: false,
+            },
+        }
+    }
+
+    async fn next(&mut self) -> Option<char> {
+        match self {
+            Reader::Keyboard {
+                scancodes,
+                keyboard,
+            } => {
+                while let Some(scancode) = scancodes.next().await {
+                    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+                        // Shiftや矢印などの文字にならないキーは無視する
+                        if let Some(DecodedKey::Unicode(character)) =
+                            keyboard.process_keyevent(key_event)
+                        {
+                            return Some(character);
+                        }
+                    }
+                }
+                None
+            }
+            Reader::Serial { bytes, last_cr } => {
+                while let Some(byte) = bytes.next().await {
+                    // 端末はEnterで"\r"か"\r\n"を送ってくるので、どちらも一回の改行にする
+                    if byte == b'\n' && *last_cr {
+                        *last_cr = false;
+                        continue;
+                    }
+                    *last_cr = byte == b'\r';
+                    return Some(if byte == b'\r' {
+                        '\n'
+                    } else {
+                        char::from(byte)
+                    });
+                }
+                None
+            }
+        }
+    }
+}
+
+/// `source`から1行ずつ読んでシェルで実行し、結果を同じ端末に返す。
+///
+/// 一つの入力元につき一度だけ呼び出すこと。
+pub async fn run_shell(source: Source) {
+    let sink = source.sink();
+    let mut out = SinkWriter(sink);
+    let mut reader = Reader::new(source);
+    let mut line = String::new();
+
+    let _ = write!(out, "{}", PROMPT);
+    while let Some(character) = reader.next().await {
+        match character {
+            '\n' => {
+                let _ = writeln!(out);
+                let _ = shell::execute_on(&line, &mut out);
+                let _ = write!(out, "\n{}", PROMPT);
+                line.clear();
+            }
+            BACKSPACE | DELETE => {
+                if line.pop().is_some() {
+                    // 一文字戻って空白で消す
+                    let _ = write!(out, "\u{8} \u{8}");
+                }
+            }
+            CTRL_L => {
+                sink.clear();
+                let _ = write!(out, "{}{}", PROMPT, line);
+            }
+            // qemuを終了
+            CTRL_C => exit_qemu(QemuExitCode::Success),
+            character if !character.is_control() => {
+                line.push(character);
+                let _ = write!(out, "{}", character);
+            }
+            _ => {}
+        }
+    }
+}
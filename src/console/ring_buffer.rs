@@ -0,0 +1,93 @@
+// 出力を固定の大きさのメモリに残す出力先
+// いっぱいになったら古いものから上書きする
+
+use super::Sink;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// `N`バイトのリングバッファ
+pub struct RingBuffer<const N: usize> {
+    name: &'static str,
+    inner: Mutex<Ring<N>>,
+}
+
+struct Ring<const N: usize> {
+    buf: [u8; N],
+    // 最も古いバイトの位置
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new(name: &'static str) -> Self {
+        RingBuffer {
+            name,
+            inner: Mutex::new(Ring {
+                buf: [0; N],
+                start: 0,
+                len: 0,
+            }),
+        }
+    }
+
+    /// 残っているバイト数を返す。
+    pub fn len(&self) -> usize {
+        interrupts::without_interrupts(|| self.inner.lock().len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        interrupts::without_interrupts(|| {
+            let mut ring = self.inner.lock();
+            ring.start = 0;
+            ring.len = 0;
+        });
+    }
+
+    /// 新しい方から最大`buf.len()`バイトを古い順に`buf`へ写し、写したバイト数を返す。
+    ///
+    /// ロック中に出力先へ書き出すと自分自身への書き込みでデッドロックするので、一度写してから使う。
+    pub fn copy_to(&self, buf: &mut [u8]) -> usize {
+        interrupts::without_interrupts(|| {
+            let ring = self.inner.lock();
+            let count = ring.len.min(buf.len());
+            let first = ring.start + (ring.len - count);
+            for (i, byte) in buf[..count].iter_mut().enumerate() {
+                *byte = ring.buf[(first + i) % N];
+            }
+            count
+        })
+    }
+
+    fn push(&self, bytes: &[u8]) {
+        interrupts::without_interrupts(|| {
+            let mut ring = self.inner.lock();
+            for &byte in bytes {
+                let end = (ring.start + ring.len) % N;
+                ring.buf[end] = byte;
+                if ring.len == N {
+                    ring.start = (ring.start + 1) % N;
+                } else {
+                    ring.len += 1;
+                }
+            }
+        });
+    }
+}
+
+impl<const N: usize> Sink for RingBuffer<N> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn write_str(&self, s: &str) {
+        self.push(s.as_bytes());
+    }
+
+    unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
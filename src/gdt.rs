@@ -1,51 +1,60 @@
+use crate::memory::stack::KernelStack;
+use conquer_once::spin::OnceCell;
+use core::mem;
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+// ISTのスタックのサイズ
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+// メモリ管理の初期化前に使うISTのスタック
+// ガードページがないので、init_stacksでガードページ付きのスタックを持つTSSに切り替える
+static mut DOUBLE_FAULT_BOOT_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref BOOT_TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            VirtAddr::from_ptr(&raw const DOUBLE_FAULT_BOOT_STACK) + IST_STACK_SIZE;
         tss
     };
+    static ref BOOT_GDT: (GlobalDescriptorTable, Selectors) = build_gdt(&BOOT_TSS);
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
-}
+// init_stacksで作る、ガードページ付きのISTのスタックを持つTSSとそれを指すGDT
+// GDTが参照した後のTSSは書き換えない
+static TSS: OnceCell<TaskStateSegment> = OnceCell::uninit();
+static GDT: OnceCell<(GlobalDescriptorTable, Selectors)> = OnceCell::uninit();
 
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-pub fn init() {
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            tss_selector,
+        },
+    )
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     #[allow(deprecated)]
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
-    let (gdt, selectors) = (&GDT.0, &GDT.1);
+    let (gdt, selectors) = (&gdt.0, &gdt.1);
 
     gdt.load();
     unsafe {
@@ -56,3 +65,30 @@ pub fn init() {
         load_tss(selectors.tss_selector);
     }
 }
+
+pub fn init() {
+    load(&BOOT_GDT);
+}
+
+/// ISTのスタックを、vmallocから確保したガードページ付きのスタックに切り替える。
+///
+/// 新しいスタックを指すTSSとGDTを作ってロードし直す。
+/// `memory::vmalloc::init`の後に一度だけ呼び出すこと。
+pub fn init_stacks() {
+    let pages = IST_STACK_SIZE / 4096;
+    let double_fault =
+        KernelStack::new("double fault", pages).expect("failed to allocate the IST stack");
+
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault.top();
+    TSS.try_init_once(|| tss)
+        .expect("init_stacks should only be called once");
+    let gdt = build_gdt(TSS.get().unwrap());
+    GDT.try_init_once(|| gdt)
+        .expect("init_stacks should only be called once");
+
+    interrupts::without_interrupts(|| load(GDT.get().unwrap()));
+
+    // ISTのスタックはカーネルが動いている間ずっと使う
+    mem::forget(double_fault);
+}
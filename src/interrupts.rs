@@ -1,17 +1,30 @@
 // "x86-interrupt"呼び出し規約は全てのレジスタを保存する => いつ関数(ハンドラ)が呼び出されるかわからない例外処理に最適
 
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptDescriptorTable;
 
-use crate::gdt;
-use crate::hlt_loop;
-use crate::println;
+use crate::apic;
+use crate::serial;
+use crate::task;
+use crate::time;
+use crate::watchdog;
+
+pub mod exception;
+pub mod irq;
+
+use irq::IrqReturn;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+// 実行中の割り込みハンドラの入れ子の深さ
+static INTERRUPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
@@ -21,32 +34,85 @@ pub static PICS: spin::Mutex<ChainedPics> =
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard, // 32 + 1 = 33
+    // COM1
+    Serial = PIC_1_OFFSET + 4,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    /// ISAのIRQ番号を返す。PICでもIO-APICでも、IRQ nは`PIC_1_OFFSET + n`のベクタに送る。
+    pub fn isa_irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
     }
 }
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+/// 割り込みや例外のハンドラの中で実行されているかを返す。
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH.load(Ordering::Relaxed) > 0
+}
 
+/// ハンドラの実行中であることを記録する。戻り値を破棄するとハンドラを抜けたことになる。
+pub(crate) fn enter_interrupt() -> InterruptGuard {
+    INTERRUPT_DEPTH.fetch_add(1, Ordering::Relaxed);
+    InterruptGuard { _private: () }
+}
+
+pub(crate) struct InterruptGuard {
+    _private: (),
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        INTERRUPT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// `vector`の割り込みの処理が終わったことを、割り込みを送ってきたコントローラに伝える。
+///
+/// `irq`に登録したハンドラの後には自動で送られるので、ハンドラから呼ぶ必要はない。
+/// ソフトウェアの`int`で起きた割り込みには送らない (コントローラが処理中の別の割り込みを終わらせてしまう)。
+pub fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        if apic::is_in_service(vector) {
+            apic::end_of_interrupt();
+        }
+    } else if pic_in_service(vector) {
         unsafe {
-            // IDTにダブルフォルトを定義したので、トリプルフォルトの発生を防ぐことができるようになる
-            // ダブルフォルトハンドラにスタックインデックスをセット
-            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        };
+            // EOI(End Of Interrupt)信号をコントローラに送る
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
+    }
+}
+
+// OCW3: 次の読み込みでISR (処理中の割り込み) を返す
+const PIC_READ_ISR: u8 = 0x0b;
+
+// `vector`の割り込みをPICが処理中か (ISRのbitが立っているか)
+pub(crate) fn pic_in_service(vector: u8) -> bool {
+    if !(PIC_1_OFFSET..PIC_2_OFFSET + 8).contains(&vector) {
+        return false;
+    }
+    let irq = vector - PIC_1_OFFSET;
+    let (command, bit) = if irq < 8 {
+        (0x20, irq)
+    } else {
+        (0xa0, irq - 8)
+    };
+    let mut port: Port<u8> = Port::new(command);
+    unsafe {
+        port.write(PIC_READ_ISR);
+        port.read() & 1 << bit != 0
+    }
+}
 
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        exception::install(&mut idt);
+        irq::install(&mut idt);
 
         idt
     };
@@ -55,56 +121,60 @@ lazy_static! {
 pub fn init_idt() {
     // idt(Interrupt Descriptor Table)を読み込む
     IDT.load();
-}
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
 
-// ダブルフォルトのハンドラ
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT!\n{:#?}", stack_frame);
+    irq::request_irq(
+        InterruptIndex::Timer.isa_irq(),
+        "timer",
+        timer_interrupt_handler,
+        ptr::null_mut(),
+    )
+    .expect("failed to register the timer interrupt");
+    irq::request_irq(
+        InterruptIndex::Keyboard.isa_irq(),
+        "keyboard",
+        keyboard_interrupt_handler,
+        ptr::null_mut(),
+    )
+    .expect("failed to register the keyboard interrupt");
+
+    // 初期化で受信割り込みが有効になるので、ハンドラより先に済ませておく
+    lazy_static::initialize(&serial::SERIAL1);
+    irq::request_irq(
+        InterruptIndex::Serial.isa_irq(),
+        "serial",
+        serial_interrupt_handler,
+        ptr::null_mut(),
+    )
+    .expect("failed to register the serial interrupt");
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn timer_interrupt_handler(_context: *mut ()) -> IrqReturn {
     // print!(".");
-
-    unsafe {
-        // EOI(End Of Interrupt)信号をコントローラに送る
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    time::tick();
+    task::timer::wake_expired();
+    watchdog::tick();
+    IrqReturn::Handled
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-
+fn keyboard_interrupt_handler(_context: *mut ()) -> IrqReturn {
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    IrqReturn::Handled
 }
 
-pub extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    // CR2はページフォールとアドレスを格納しているレジスタ
-    use x86_64::registers::control::Cr2;
-
-    println!("EXEPTION: PAGE FAULT!");
-    // Cr2::read()でアクセスしようとしたアドレスを呼び出す
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+fn serial_interrupt_handler(_context: *mut ()) -> IrqReturn {
+    // FIFOに溜まった分を全て読む
+    let mut received = false;
+    while let Some(byte) = serial::try_read_byte() {
+        task::serial::add_byte(byte);
+        received = true;
+    }
+    if received {
+        IrqReturn::Handled
+    } else {
+        IrqReturn::NotMine
+    }
 }
 
 #[test_case]
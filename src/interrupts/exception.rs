@@ -0,0 +1,408 @@
+// CPUの例外のハンドラ
+// 回復できない例外は、どれも同じ形式の報告をVGAとシリアルの両方に出してからpanicする
+
+use crate::backtrace::{self, Backtrace};
+use crate::memory;
+use crate::{console, gdt, print, println};
+use core::arch::global_asm;
+use core::fmt;
+use core::mem;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+/// 全ての例外のハンドラをIDTに登録する。
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    // 実行を続けるトラップ
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+
+    // それ以外は汎用レジスタを積む入口のスタブから`exception_entry`に入る
+    unsafe {
+        idt.divide_error.set_handler_fn(stub(exception_stub_0));
+        idt.overflow.set_handler_fn(stub(exception_stub_4));
+        idt.bound_range_exceeded
+            .set_handler_fn(stub(exception_stub_5));
+        idt.invalid_opcode.set_handler_fn(stub(exception_stub_6));
+        idt.device_not_available
+            .set_handler_fn(stub(exception_stub_7));
+        // IDTにダブルフォルトを定義したので、トリプルフォルトの発生を防ぐことができるようになる
+        // ダブルフォルトハンドラにスタックインデックスをセット
+        idt.double_fault
+            .set_handler_fn(stub(exception_stub_8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_fn(stub(exception_stub_10));
+        idt.segment_not_present
+            .set_handler_fn(stub(exception_stub_11));
+        idt.stack_segment_fault
+            .set_handler_fn(stub(exception_stub_12));
+        idt.general_protection_fault
+            .set_handler_fn(stub(exception_stub_13));
+        // ページフォルトはハンドラの中で入れ子になる (遅延マップやコピーオンライト) ので、ISTは使わない
+        // スタックオーバーフローでハンドラを呼べなければダブルフォルトになる
+        idt.page_fault.set_handler_fn(stub(exception_stub_14));
+        idt.x87_floating_point
+            .set_handler_fn(stub(exception_stub_16));
+        idt.alignment_check.set_handler_fn(stub(exception_stub_17));
+        idt.machine_check.set_handler_fn(stub(exception_stub_18));
+        idt.simd_floating_point
+            .set_handler_fn(stub(exception_stub_19));
+        idt.virtualization.set_handler_fn(stub(exception_stub_20));
+        idt.security_exception
+            .set_handler_fn(stub(exception_stub_30));
+    }
+}
+
+// スタブのアドレスをハンドラの型にする
+// スタブは"x86-interrupt"の関数ではないが、IDTにはアドレスしか書き込まれない
+// この関数はunsafeである：渡すのは下のglobal_asm!で定義したスタブでなければならない
+unsafe fn stub<F: Copy>(entry: unsafe extern "C" fn()) -> F {
+    assert_eq!(
+        mem::size_of::<F>(),
+        mem::size_of::<unsafe extern "C" fn()>()
+    );
+    mem::transmute_copy(&entry)
+}
+
+/// 例外のエラーコード
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorCode {
+    /// エラーコードを積まない例外
+    None,
+    /// 意味を持たない、または解釈しないエラーコード
+    Raw(u64),
+    /// セグメントセレクタを指すエラーコード (#TS, #NP, #SS, #GP)
+    Selector(u64),
+    PageFault(PageFaultErrorCode),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorCode::None => write!(f, "none"),
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+            ErrorCode::Selector(0) => write!(f, "0x0"),
+            ErrorCode::Selector(code) => {
+                let table = match (code >> 1) & 0b11 {
+                    0b00 => "GDT",
+                    0b10 => "LDT",
+                    _ => "IDT",
+                };
+                write!(
+                    f,
+                    "{:#x} ({} index {}{})",
+                    code,
+                    table,
+                    (code >> 3) & 0x1fff,
+                    if code & 1 != 0 { ", external" } else { "" }
+                )
+            }
+            ErrorCode::PageFault(code) => write!(f, "{:#x} {:?}", code.bits(), code),
+        }
+    }
+}
+
+/// 例外が起きたときの汎用レジスタ
+///
+/// Rustのコードが動く前に入口のスタブが積んだ値で、例外の時点のものと同じである。
+/// 並びはスタブが積む順番と逆になっている。
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+// 入口のスタブがスタックに作る例外の状態
+// エラーコードを積まない例外では、スタブが0を積んで形をそろえる
+#[repr(C)]
+struct ExceptionState {
+    registers: Registers,
+    vector: u64,
+    error_code: u64,
+    stack_frame: InterruptStackFrame,
+}
+
+// 例外ごとの入口
+// 汎用レジスタを積んで`exception_entry`を呼び、戻ってきたら元に戻してiretqで再実行する
+// CPUは例外のフレームを積む前にrspを16バイトにそろえるので、
+// フレーム (5個) とエラーコード、ベクタ、汎用レジスタ (15個) で合計22個を積むと
+// callの時点でもそろっている
+global_asm!(
+    r#"
+.macro exception_stub vector, has_error_code
+.global exception_stub_\vector
+exception_stub_\vector:
+.if \has_error_code == 0
+    push 0
+.endif
+    push \vector
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rbp
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+    mov rdi, rsp
+    call {entry}
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rbp
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    add rsp, 16
+    iretq
+.endm
+
+exception_stub 0, 0
+exception_stub 4, 0
+exception_stub 5, 0
+exception_stub 6, 0
+exception_stub 7, 0
+exception_stub 8, 1
+exception_stub 10, 1
+exception_stub 11, 1
+exception_stub 12, 1
+exception_stub 13, 1
+exception_stub 14, 1
+exception_stub 16, 0
+exception_stub 17, 1
+exception_stub 18, 0
+exception_stub 19, 0
+exception_stub 20, 0
+exception_stub 30, 1
+"#,
+    entry = sym exception_entry,
+);
+
+extern "C" {
+    fn exception_stub_0();
+    fn exception_stub_4();
+    fn exception_stub_5();
+    fn exception_stub_6();
+    fn exception_stub_7();
+    fn exception_stub_8();
+    fn exception_stub_10();
+    fn exception_stub_11();
+    fn exception_stub_12();
+    fn exception_stub_13();
+    fn exception_stub_14();
+    fn exception_stub_16();
+    fn exception_stub_17();
+    fn exception_stub_18();
+    fn exception_stub_19();
+    fn exception_stub_20();
+    fn exception_stub_30();
+}
+
+/// 例外の報告
+pub struct ExceptionReport<'a> {
+    pub name: &'static str,
+    pub vector: u8,
+    pub stack_frame: &'a InterruptStackFrame,
+    pub error_code: ErrorCode,
+    pub registers: Registers,
+}
+
+impl fmt::Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = self.stack_frame;
+        let r = &self.registers;
+        let cr2 = Cr2::read();
+        let (cr3, _) = Cr3::read();
+
+        writeln!(f, "EXCEPTION: {} (vector {})", self.name, self.vector)?;
+        writeln!(f, "error code: {}", self.error_code)?;
+        // ガードページに触れた場合
+        if let Some(name) = memory::stack::overflowed_stack(cr2) {
+            writeln!(f, "stack overflow in {}", name)?;
+        }
+        writeln!(
+            f,
+            "RIP {:#018x} CS {:#06x} RFLAGS {:#010x}",
+            frame.instruction_pointer.as_u64(),
+            frame.code_segment,
+            frame.cpu_flags
+        )?;
+        writeln!(
+            f,
+            "RSP {:#018x} SS {:#06x}",
+            frame.stack_pointer.as_u64(),
+            frame.stack_segment
+        )?;
+        writeln!(
+            f,
+            "CR0 {:#x} CR2 {:#x} CR3 {:#x} CR4 {:#x}",
+            Cr0::read_raw(),
+            cr2.as_u64(),
+            cr3.start_address().as_u64(),
+            Cr4::read_raw()
+        )?;
+        writeln!(
+            f,
+            "RAX {:#018x} RBX {:#018x} RCX {:#018x}",
+            r.rax, r.rbx, r.rcx
+        )?;
+        writeln!(
+            f,
+            "RDX {:#018x} RSI {:#018x} RDI {:#018x}",
+            r.rdx, r.rsi, r.rdi
+        )?;
+        writeln!(
+            f,
+            "RBP {:#018x} R8  {:#018x} R9  {:#018x}",
+            r.rbp, r.r8, r.r9
+        )?;
+        writeln!(
+            f,
+            "R10 {:#018x} R11 {:#018x} R12 {:#018x}",
+            r.r10, r.r11, r.r12
+        )?;
+        writeln!(
+            f,
+            "R13 {:#018x} R14 {:#018x} R15 {:#018x}",
+            r.r13, r.r14, r.r15
+        )?;
+
+        // 例外が起きた命令と、その呼び出し元
+        write!(f, "at ")?;
+        backtrace::write_address(f, frame.instruction_pointer.as_u64())?;
+        writeln!(f)?;
+        write!(f, "{}", Backtrace::from_frame_pointer(r.rbp))
+    }
+}
+
+/// 報告をコンソールの全ての出力先に出してからpanicする。
+pub fn fatal(report: ExceptionReport) -> ! {
+    // 例外が起きた処理には戻らないので、出力先のロックを外して直接書き出す
+    unsafe { console::enter_panic_mode() };
+    print!("{}", report);
+    panic!("EXCEPTION: {}", report.name);
+}
+
+// 入口のスタブから呼ばれる
+// 戻ると例外を起こした命令を再実行するので、回復できない例外はここで`fatal`に渡す
+extern "C" fn exception_entry(state: &ExceptionState) {
+    let code = state.error_code;
+    let (name, error_code) = match state.vector {
+        0 => ("DIVIDE ERROR", ErrorCode::None),
+        4 => ("OVERFLOW", ErrorCode::None),
+        5 => ("BOUND RANGE EXCEEDED", ErrorCode::None),
+        6 => ("INVALID OPCODE", ErrorCode::None),
+        7 => ("DEVICE NOT AVAILABLE", ErrorCode::None),
+        // スタックオーバーフローでページフォルトのハンドラを呼べなかった場合、CR2にはガードページの
+        // アドレスが残っているので、報告にはどのスタックが溢れたかも含まれる
+        8 => ("DOUBLE FAULT", ErrorCode::Raw(code)),
+        10 => ("INVALID TSS", ErrorCode::Selector(code)),
+        11 => ("SEGMENT NOT PRESENT", ErrorCode::Selector(code)),
+        12 => ("STACK SEGMENT FAULT", ErrorCode::Selector(code)),
+        13 => ("GENERAL PROTECTION FAULT", ErrorCode::Selector(code)),
+        14 => {
+            let code = PageFaultErrorCode::from_bits_truncate(code);
+            if handle_page_fault(code) {
+                return;
+            }
+            ("PAGE FAULT", ErrorCode::PageFault(code))
+        }
+        16 => ("X87 FLOATING POINT", ErrorCode::None),
+        17 => ("ALIGNMENT CHECK", ErrorCode::Raw(code)),
+        18 => ("MACHINE CHECK", ErrorCode::None),
+        19 => ("SIMD FLOATING POINT", ErrorCode::None),
+        20 => ("VIRTUALIZATION", ErrorCode::None),
+        30 => ("SECURITY EXCEPTION", ErrorCode::Raw(code)),
+        _ => ("UNKNOWN", ErrorCode::Raw(code)),
+    };
+
+    fatal(ExceptionReport {
+        name,
+        vector: state.vector as u8,
+        stack_frame: &state.stack_frame,
+        error_code,
+        registers: state.registers,
+    });
+}
+
+// 処理できるページフォルトならページを用意してtrueを返す
+fn handle_page_fault(error_code: PageFaultErrorCode) -> bool {
+    // CR2はページフォールトのアドレスを格納しているレジスタ
+    let address = Cr2::read();
+
+    // 予約済みでまだマップしていないページなら、マップしてから命令を再実行する
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && memory::vmalloc::handle_page_fault(address)
+    {
+        return true;
+    }
+    // コピーオンライトのページへの書き込みなら、複製してから命令を再実行する
+    error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && memory::cow::handle_write_fault(address)
+}
+
+// 実行を続ける例外の報告は、割り込みハンドラの出力として1件に収まるよう1行にまとめる
+struct CompactFrame<'a>(&'a InterruptStackFrame);
+
+impl fmt::Display for CompactFrame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = self.0;
+        write!(
+            f,
+            "RIP {:#x} CS {:#x} RFLAGS {:#x} RSP {:#x} SS {:#x}",
+            frame.instruction_pointer.as_u64(),
+            frame.code_segment,
+            frame.cpu_flags,
+            frame.stack_pointer.as_u64(),
+            frame.stack_segment
+        )
+    }
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    let _guard = super::enter_interrupt();
+    println!("EXCEPTION: DEBUG {}", CompactFrame(&stack_frame));
+}
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let _guard = super::enter_interrupt();
+    println!(
+        "EXCEPTION: NON-MASKABLE INTERRUPT {}",
+        CompactFrame(&stack_frame)
+    );
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _guard = super::enter_interrupt();
+    println!("EXCEPTION: BREAKPOINT {}", CompactFrame(&stack_frame));
+}
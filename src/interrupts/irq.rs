@@ -0,0 +1,549 @@
+// 割り込みハンドラの動的な登録
+// IDTはベクタごとの入口を並べた固定のもので、入口は登録されたハンドラを順に呼び出してからEOIを送る
+// 一つのベクタに複数のハンドラを登録して、割り込みの線を共有できる
+
+use super::{end_of_interrupt, pic_in_service, PIC_1_OFFSET, PIC_2_OFFSET};
+use crate::apic;
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr;
+use core::sync::atomic::{self, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+/// ISAの割り込みの線の数。IRQ nのベクタは`PIC_1_OFFSET + n`になる。
+pub const ISA_IRQ_COUNT: u8 = 16;
+
+/// ISAのIRQ 0のベクタ
+pub const FIRST_ISA_VECTOR: u8 = PIC_1_OFFSET;
+
+/// `allocate_vector`で割り当てるベクタの範囲
+pub const FIRST_DYNAMIC_VECTOR: u8 = PIC_1_OFFSET + ISA_IRQ_COUNT;
+pub const LAST_DYNAMIC_VECTOR: u8 = FIRST_DYNAMIC_VECTOR + 31;
+
+// ここで扱うベクタの数 (ISAの割り込みと動的に割り当てるベクタ)
+const VECTOR_COUNT: usize = (LAST_DYNAMIC_VECTOR - PIC_1_OFFSET) as usize + 1;
+
+// 一つのベクタに登録できるハンドラの数
+const MAX_HANDLERS: usize = 4;
+
+/// ハンドラが割り込みを処理したか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    /// 線を共有している別の装置の割り込みだった
+    NotMine,
+}
+
+/// 登録するハンドラ。`context`には登録時に渡したポインタが渡される。
+pub type HandlerFn = fn(context: *mut ()) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq,
+    InvalidVector,
+    TooManyHandlers,
+    NoFreeVector,
+    NotRegistered,
+}
+
+/// 登録したハンドラを表す。`free_handler`で登録を解除する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    id: u64,
+}
+
+impl HandlerId {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Handler {
+    id: u64,
+    name: &'static str,
+    func: HandlerFn,
+    context: *mut (),
+    // クロージャを登録した場合、登録の解除でcontextを解放する
+    drop_context: Option<unsafe fn(*mut ())>,
+}
+
+// contextの扱いは登録した側が責任を持つ
+unsafe impl Send for Handler {}
+
+#[derive(Clone, Copy)]
+struct Line {
+    handlers: [Option<Handler>; MAX_HANDLERS],
+    // allocate_vectorで割り当て済みか
+    allocated: bool,
+}
+
+impl Line {
+    const EMPTY: Line = Line {
+        handlers: [None; MAX_HANDLERS],
+        allocated: false,
+    };
+
+    fn is_empty(&self) -> bool {
+        self.handlers.iter().all(Option::is_none)
+    }
+}
+
+// 登録と解除は割り込みを無効にして行う
+static LINES: Mutex<[Line; VECTOR_COUNT]> = Mutex::new([Line::EMPTY; VECTOR_COUNT]);
+
+// 割り込みハンドラから読むための、ベクタごとのハンドラの写し
+// LINESを書き換えたときにseqlockで公開し、dispatchはロックを取らずに読む
+struct Published {
+    // 奇数なら書き換え中
+    seq: AtomicU64,
+    handlers: UnsafeCell<[Option<Handler>; MAX_HANDLERS]>,
+}
+
+unsafe impl Sync for Published {}
+
+impl Published {
+    const EMPTY: Published = Published {
+        seq: AtomicU64::new(0),
+        handlers: UnsafeCell::new([None; MAX_HANDLERS]),
+    };
+
+    // LINESのロックを持って呼ぶので、書き込むのは一度に一箇所だけ
+    fn publish(&self, handlers: [Option<Handler>; MAX_HANDLERS]) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.handlers.get(), handlers) };
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+
+    fn read(&self) -> [Option<Handler>; MAX_HANDLERS] {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq % 2 == 0 {
+                // 書き換えと重なった場合は壊れた値を読むので、確かめるまでは値として扱わない
+                let handlers = unsafe {
+                    ptr::read_volatile(
+                        self.handlers.get() as *const MaybeUninit<[Option<Handler>; MAX_HANDLERS]>
+                    )
+                };
+                atomic::fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == seq {
+                    return unsafe { handlers.assume_init() };
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+static PUBLISHED: [Published; VECTOR_COUNT] = [Published::EMPTY; VECTOR_COUNT];
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; VECTOR_COUNT] = [ZERO; VECTOR_COUNT];
+// どのハンドラも処理しなかった割り込みと、PIC、Local APICの偽の割り込みの回数
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+// ベクタごとの、ハンドラを呼び出している最中のdispatchの数
+static ACTIVE: [AtomicU64; VECTOR_COUNT] = [ZERO; VECTOR_COUNT];
+
+// 登録を解除したが、まだcontextを解放していないハンドラ (ベクタ, ハンドラ)
+// dispatchが呼び出している最中や割り込みハンドラの中で解除した場合はここに置き、
+// 割り込みハンドラの外で次に登録や解除をするときに解放する
+const MAX_RETIRED: usize = 16;
+static RETIRED: Mutex<[Option<(u8, Handler)>; MAX_RETIRED]> = Mutex::new([None; MAX_RETIRED]);
+
+/// ISAの`irq`にハンドラを登録し、割り込みを受け付けるようにする。
+///
+/// 割り込みが来ると`handler(context)`が呼ばれる。同じ線に登録されたハンドラは登録順に全て呼ばれる。
+pub fn request_irq(
+    irq: u8,
+    name: &'static str,
+    handler: HandlerFn,
+    context: *mut (),
+) -> Result<HandlerId, IrqError> {
+    if irq >= ISA_IRQ_COUNT {
+        return Err(IrqError::InvalidIrq);
+    }
+    let id = insert(PIC_1_OFFSET + irq, name, handler, context, None)?;
+    enable_isa_irq(irq);
+    Ok(id)
+}
+
+/// ISAの`irq`にクロージャを登録する。ヒープを使う。
+pub fn request_irq_with<F>(irq: u8, name: &'static str, f: F) -> Result<HandlerId, IrqError>
+where
+    F: FnMut() -> IrqReturn + Send + 'static,
+{
+    if irq >= ISA_IRQ_COUNT {
+        return Err(IrqError::InvalidIrq);
+    }
+    let id = insert_closure(PIC_1_OFFSET + irq, name, f)?;
+    enable_isa_irq(irq);
+    Ok(id)
+}
+
+/// 使われていないベクタを割り当てる。
+pub fn allocate_vector() -> Result<u8, IrqError> {
+    with_lines(|lines| {
+        let vector = (FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR)
+            .find(|&vector| !lines[index(vector)].allocated)
+            .ok_or(IrqError::NoFreeVector)?;
+        lines[index(vector)].allocated = true;
+        Ok(vector)
+    })
+}
+
+/// `allocate_vector`で割り当てたベクタを返す。登録されていたハンドラは全て解除する。
+pub fn free_vector(vector: u8) -> Result<(), IrqError> {
+    if !is_dynamic(vector) {
+        return Err(IrqError::InvalidVector);
+    }
+    reap_retired();
+    let line = with_lines(|lines| {
+        let line = lines[index(vector)];
+        lines[index(vector)] = Line::EMPTY;
+        publish(vector, lines);
+        line
+    });
+    if !line.allocated {
+        return Err(IrqError::NotRegistered);
+    }
+    for handler in line.handlers.iter().flatten() {
+        release(vector, *handler);
+    }
+    Ok(())
+}
+
+/// `allocate_vector`で割り当てたベクタにハンドラを登録する。
+pub fn register(
+    vector: u8,
+    name: &'static str,
+    handler: HandlerFn,
+    context: *mut (),
+) -> Result<HandlerId, IrqError> {
+    check_allocated(vector)?;
+    insert(vector, name, handler, context, None)
+}
+
+/// `allocate_vector`で割り当てたベクタにクロージャを登録する。ヒープを使う。
+pub fn register_with<F>(vector: u8, name: &'static str, f: F) -> Result<HandlerId, IrqError>
+where
+    F: FnMut() -> IrqReturn + Send + 'static,
+{
+    check_allocated(vector)?;
+    insert_closure(vector, name, f)
+}
+
+/// ハンドラの登録を解除する。ISAの線のハンドラが無くなれば、その割り込みを止める。
+///
+/// ハンドラの中から自分自身を解除してもよい。登録したクロージャは、そのベクタのハンドラの
+/// 呼び出しが全て終わってから解放される。
+pub fn free_handler(handler: HandlerId) -> Result<(), IrqError> {
+    let vector = handler.vector;
+    reap_retired();
+    let (removed, now_empty) = with_lines(|lines| {
+        let line = &mut lines[index(vector)];
+        let removed = line
+            .handlers
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(entry) if entry.id == handler.id))
+            .and_then(Option::take);
+        let now_empty = line.is_empty();
+        publish(vector, lines);
+        (removed, now_empty)
+    });
+    let removed = removed.ok_or(IrqError::NotRegistered)?;
+    release(vector, removed);
+
+    if now_empty && vector < FIRST_DYNAMIC_VECTOR {
+        disable_isa_irq(vector - PIC_1_OFFSET);
+    }
+    Ok(())
+}
+
+/// ISAの`irq`にハンドラが登録されているかを返す。
+pub fn is_requested(irq: u8) -> bool {
+    irq < ISA_IRQ_COUNT && with_lines(|lines| !lines[index(PIC_1_OFFSET + irq)].is_empty())
+}
+
+/// `vector`の割り込みが起きた回数を返す。
+pub fn count(vector: u8) -> u64 {
+    if is_managed(vector) {
+        COUNTS[index(vector)].load(Ordering::Relaxed)
+    } else {
+        0
+    }
+}
+
+/// 偽の割り込みと、どのハンドラも処理しなかった割り込みの回数を返す。
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// `vector`に登録されているハンドラの名前を順に渡す。
+pub fn for_each_handler(vector: u8, mut f: impl FnMut(&'static str)) {
+    if !is_managed(vector) {
+        return;
+    }
+    let line = with_lines(|lines| lines[index(vector)]);
+    for handler in line.handlers.iter().flatten() {
+        f(handler.name);
+    }
+}
+
+/// ここで扱う全てのベクタの入口と、Local APICの偽の割り込みのハンドラをIDTに登録する。
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    macro_rules! install_entries {
+        ($($vector:literal),*) => {
+            $(idt[$vector].set_handler_fn(entry::<$vector>);)*
+        };
+    }
+    install_entries!(
+        32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54,
+        55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77,
+        78, 79
+    );
+    idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_handler);
+}
+
+// ベクタごとの入口
+extern "x86-interrupt" fn entry<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(VECTOR);
+}
+
+fn dispatch(vector: u8) {
+    let _guard = super::enter_interrupt();
+    COUNTS[index(vector)].fetch_add(1, Ordering::Relaxed);
+
+    if is_spurious_pic_interrupt(vector) {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        // スレーブの偽の割り込みでも、マスターはカスケードの割り込みを受け付けている
+        if vector == PIC_2_OFFSET + 7 {
+            unsafe { Port::<u8>::new(0x20).write(PIC_EOI) };
+        }
+        return;
+    }
+
+    // 登録や解除の最中でも待たずに読める
+    // 読んだハンドラのcontextは、呼び出し終わるまで解放されない
+    let active = &ACTIVE[index(vector)];
+    active.fetch_add(1, Ordering::SeqCst);
+    let handlers = PUBLISHED[index(vector)].read();
+    let mut handled = false;
+    for handler in handlers.iter().flatten() {
+        if (handler.func)(handler.context) == IrqReturn::Handled {
+            handled = true;
+        }
+    }
+    active.fetch_sub(1, Ordering::SeqCst);
+    if !handled {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    end_of_interrupt(vector);
+}
+
+// Local APICの偽の割り込み。EOIを送ってはいけない
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+const PIC_EOI: u8 = 0x20;
+
+// PICはIRQ 7と15で偽の割り込みを起こすことがある。その場合ISRのbitが立っていない
+fn is_spurious_pic_interrupt(vector: u8) -> bool {
+    if apic::is_enabled() {
+        return false;
+    }
+    (vector == PIC_1_OFFSET + 7 || vector == PIC_2_OFFSET + 7) && !pic_in_service(vector)
+}
+
+fn enable_isa_irq(irq: u8) {
+    if apic::is_enabled() {
+        apic::enable_isa_irq(irq);
+    } else {
+        set_pic_mask(irq, false);
+        // スレーブのPICはマスターのIRQ 2に繋がっている
+        if irq >= 8 {
+            set_pic_mask(2, false);
+        }
+    }
+}
+
+fn disable_isa_irq(irq: u8) {
+    if apic::is_enabled() {
+        apic::disable_isa_irq(irq);
+    } else {
+        set_pic_mask(irq, true);
+    }
+}
+
+fn set_pic_mask(irq: u8, masked: bool) {
+    let (mut port, bit) = if irq < 8 {
+        (Port::<u8>::new(0x21), irq)
+    } else {
+        (Port::<u8>::new(0xa1), irq - 8)
+    };
+    interrupts::without_interrupts(|| unsafe {
+        let mask = port.read();
+        if masked {
+            port.write(mask | 1 << bit);
+        } else {
+            port.write(mask & !(1 << bit));
+        }
+    });
+}
+
+fn insert(
+    vector: u8,
+    name: &'static str,
+    func: HandlerFn,
+    context: *mut (),
+    drop_context: Option<unsafe fn(*mut ())>,
+) -> Result<HandlerId, IrqError> {
+    reap_retired();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    with_lines(|lines| {
+        let slot = lines[index(vector)]
+            .handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::TooManyHandlers)?;
+        *slot = Some(Handler {
+            id,
+            name,
+            func,
+            context,
+            drop_context,
+        });
+        publish(vector, lines);
+        Ok(HandlerId { vector, id })
+    })
+}
+
+fn insert_closure<F>(vector: u8, name: &'static str, f: F) -> Result<HandlerId, IrqError>
+where
+    F: FnMut() -> IrqReturn + Send + 'static,
+{
+    let context = Box::into_raw(Box::new(f)) as *mut ();
+    insert(
+        vector,
+        name,
+        call_closure::<F>,
+        context,
+        Some(drop_closure::<F>),
+    )
+    .map_err(|err| {
+        unsafe { drop_closure::<F>(context) };
+        err
+    })
+}
+
+fn call_closure<F: FnMut() -> IrqReturn>(context: *mut ()) -> IrqReturn {
+    let f = unsafe { &mut *(context as *mut F) };
+    f()
+}
+
+unsafe fn drop_closure<F>(context: *mut ()) {
+    drop(Box::from_raw(context as *mut F));
+}
+
+fn drop_context(handler: &Handler) {
+    if let Some(drop_context) = handler.drop_context {
+        unsafe { drop_context(handler.context) };
+    }
+}
+
+// 登録を解除したハンドラのcontextを解放する
+// 解放はヒープを使うので割り込みハンドラの中ではせず、dispatchが呼び出している最中のものも後回しにする
+fn release(vector: u8, handler: Handler) {
+    if handler.drop_context.is_none() {
+        return;
+    }
+    // 解除を公開してから数えるので、ここで0ならこの後のdispatchはハンドラを読まない
+    atomic::fence(Ordering::SeqCst);
+    if !super::in_interrupt() && is_idle(vector) {
+        drop_context(&handler);
+        return;
+    }
+    // 置き場所がなければ解放しないまま残す (呼び出し中のクロージャを解放するよりよい)
+    with_retired(|retired| {
+        if let Some(slot) = retired.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some((vector, handler));
+        }
+    });
+}
+
+// dispatchが`vector`のハンドラを呼び出していないか
+fn is_idle(vector: u8) -> bool {
+    ACTIVE[index(vector)].load(Ordering::SeqCst) == 0
+}
+
+// 後回しにしたcontextのうち、もう呼ばれないものを解放する
+fn reap_retired() {
+    if super::in_interrupt() {
+        return;
+    }
+    loop {
+        let retired = with_retired(|retired| {
+            retired
+                .iter_mut()
+                .find(|slot| slot.map_or(false, |(vector, _)| is_idle(vector)))
+                .and_then(Option::take)
+        });
+        // ロックを外してから解放する
+        match retired {
+            Some((_, handler)) => drop_context(&handler),
+            None => break,
+        }
+    }
+}
+
+fn check_allocated(vector: u8) -> Result<(), IrqError> {
+    if !is_dynamic(vector) {
+        return Err(IrqError::InvalidVector);
+    }
+    if with_lines(|lines| lines[index(vector)].allocated) {
+        Ok(())
+    } else {
+        Err(IrqError::NotRegistered)
+    }
+}
+
+fn is_managed(vector: u8) -> bool {
+    (PIC_1_OFFSET..=LAST_DYNAMIC_VECTOR).contains(&vector)
+}
+
+fn is_dynamic(vector: u8) -> bool {
+    (FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR).contains(&vector)
+}
+
+fn index(vector: u8) -> usize {
+    usize::from(vector - PIC_1_OFFSET)
+}
+
+// LINESの`vector`の行を割り込みハンドラから読めるようにする
+fn publish(vector: u8, lines: &[Line; VECTOR_COUNT]) {
+    PUBLISHED[index(vector)].publish(lines[index(vector)].handlers);
+}
+
+fn with_retired<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut [Option<(u8, Handler)>; MAX_RETIRED]) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut RETIRED.lock()))
+}
+
+fn with_lines<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut [Line; VECTOR_COUNT]) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut LINES.lock()))
+}
@@ -16,13 +16,21 @@ extern crate alloc;
 
 use core::panic::PanicInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
+pub mod console;
 pub mod gdt;
 pub mod interrupts;
+pub mod logger;
 pub mod memory;
 pub mod serial;
+pub mod shell;
 pub mod task;
+pub mod time;
 pub mod vga_buffer;
+pub mod watchdog;
 
 pub trait TestTable {
     fn run(&self) -> ();
@@ -49,8 +57,10 @@ pub fn test_runner(tests: &[&dyn TestTable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    unsafe { console::enter_panic_mode() };
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_print!("{}", backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -80,11 +90,13 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 }
 
 pub fn init() {
+    logger::init();
     gdt::init();
     interrupts::init_idt();
     unsafe {
         interrupts::PICS.lock().initialize();
     }
+    time::init();
     // 割り込みコントローラからの信号を受け入れる
     // タイム割り込みのハンドラ未定義のためダブルフォルト発生
     x86_64::instructions::interrupts::enable();
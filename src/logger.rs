@@ -0,0 +1,248 @@
+// `log`クレートのロガー
+// ログは起動からの時間とともに固定の大きさのリングバッファ (dmesg) に残し、コンソールにも表示する
+// 割り込みを止めてからロックを取り、ヒープも使わないので、割り込みハンドラからも呼べる
+// (ハンドラの中でロックが取れなければ、そのログは捨てる)
+
+use crate::console::utf8_prefix;
+use crate::{println, time};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// リングバッファに残すログの数
+const CAPACITY: usize = 256;
+// 1件に残すターゲットとメッセージの長さ。越えた分は切り捨てる
+const TARGET_LEN: usize = 32;
+const MESSAGE_LEN: usize = 120;
+
+// これより重要なログだけをコンソールに表示する (リングバッファには全て残す)
+static CONSOLE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+
+static LOGGER: Logger = Logger;
+static LOG: Mutex<LogBuffer> = Mutex::new(LogBuffer {
+    entries: [Entry::EMPTY; CAPACITY],
+    next_seq: 0,
+});
+
+/// リングバッファに残った1件のログ
+#[derive(Clone, Copy)]
+pub struct Entry {
+    /// 起動してからの通し番号
+    pub seq: u64,
+    /// 記録したときのタイマ割り込みの回数
+    pub ticks: u64,
+    pub level: Level,
+    target: [u8; TARGET_LEN],
+    target_len: usize,
+    message: [u8; MESSAGE_LEN],
+    message_len: usize,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        seq: 0,
+        ticks: 0,
+        level: Level::Trace,
+        target: [0; TARGET_LEN],
+        target_len: 0,
+        message: [0; MESSAGE_LEN],
+        message_len: 0,
+    };
+
+    /// ログを出したモジュール
+    pub fn target(&self) -> &str {
+        utf8_prefix(&self.target[..self.target_len])
+    }
+
+    pub fn message(&self) -> &str {
+        utf8_prefix(&self.message[..self.message_len])
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = time::ticks_to_duration(self.ticks);
+        write!(
+            f,
+            "[{:>5}.{:03}] {:<5} {}: {}",
+            time.as_secs(),
+            time.subsec_millis(),
+            self.level,
+            self.target(),
+            self.message()
+        )
+    }
+}
+
+struct LogBuffer {
+    entries: [Entry; CAPACITY],
+    // 次に書き込むログの通し番号
+    next_seq: u64,
+}
+
+impl LogBuffer {
+    fn push(&mut self, entry: Entry) {
+        let seq = self.next_seq;
+        self.entries[seq as usize % CAPACITY] = Entry { seq, ..entry };
+        self.next_seq += 1;
+    }
+
+    // 上書きされずに残っている最も古い通し番号
+    fn first_seq(&self) -> u64 {
+        self.next_seq.saturating_sub(CAPACITY as u64)
+    }
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut entry = Entry {
+            ticks: time::ticks(),
+            level: record.level(),
+            ..Entry::EMPTY
+        };
+        entry.target_len = copy_truncated(&mut entry.target, record.target());
+        let mut message = Truncate {
+            buf: &mut entry.message,
+            len: 0,
+        };
+        let _ = message.write_fmt(*record.args());
+        entry.message_len = message.len;
+
+        let seq = interrupts::without_interrupts(|| {
+            // NMIなどがログの書き込み中に割り込んだ場合は、待たずに諦める
+            let mut log = if crate::interrupts::in_interrupt() {
+                LOG.try_lock()?
+            } else {
+                LOG.lock()
+            };
+            log.push(entry);
+            Some(log.next_seq - 1)
+        });
+
+        // 割り込みハンドラの中では、コンソールへの出力はタスクの側に回される
+        if let Some(seq) = seq {
+            if record.level() <= console_level() {
+                println!("{}", Entry { seq, ..entry });
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// ロガーを登録する。他の初期化より先に呼び出すこと。
+pub fn init() {
+    log::set_logger(&LOGGER).expect("logger already initialized");
+    log::set_max_level(LevelFilter::Trace);
+}
+
+/// コンソールに表示するログの重要度を設定する。
+pub fn set_console_level(level: LevelFilter) {
+    CONSOLE_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn console_level() -> LevelFilter {
+    match CONSOLE_LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// リングバッファに残っている`level`以上に重要なログを、古い順に`f`に渡す。
+///
+/// 1件ずつ写してからロックを外して渡すので、`f`の中でログを出してもよい。
+pub fn for_each(level: LevelFilter, mut f: impl FnMut(&Entry)) {
+    let (mut seq, mut end) = interrupts::without_interrupts(|| {
+        let log = LOG.lock();
+        (log.first_seq(), log.next_seq)
+    });
+    while seq < end {
+        // 渡している間に上書きされたら、残っている中で最も古いものから続ける
+        let entry = interrupts::without_interrupts(|| {
+            let log = LOG.lock();
+            seq = seq.max(log.first_seq());
+            end = end.min(log.next_seq);
+            log.entries[seq as usize % CAPACITY]
+        });
+        if seq >= end {
+            break;
+        }
+        if entry.level <= level {
+            f(&entry);
+        }
+        seq += 1;
+    }
+}
+
+/// これまでに上書きされて失われたログの数を返す。
+pub fn lost() -> u64 {
+    interrupts::without_interrupts(|| LOG.lock().first_seq())
+}
+
+// 書き込める分だけ書き込み、残りは捨てるWriter
+struct Truncate<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for Truncate<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.len += copy_truncated(&mut self.buf[self.len..], s);
+        Ok(())
+    }
+}
+
+fn copy_truncated(buf: &mut [u8], s: &str) -> usize {
+    let len = s.len().min(buf.len());
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    len
+}
+
+#[test_case]
+fn log_is_recorded_with_target() {
+    log::debug!("logger test {}", 1);
+    let mut found = None;
+    for_each(LevelFilter::Trace, |entry| {
+        if entry.message() == "logger test 1" {
+            found = Some(*entry);
+        }
+    });
+    let entry = found.expect("log entry not found");
+    assert_eq!(entry.level, Level::Debug);
+    assert_eq!(entry.target(), module_path!());
+
+    // Debugのログは、Info以上の絞り込みでは出てこない
+    let mut count = 0;
+    for_each(LevelFilter::Info, |entry| {
+        if entry.message() == "logger test 1" {
+            count += 1;
+        }
+    });
+    assert_eq!(count, 0);
+}
+
+#[test_case]
+fn long_message_is_truncated() {
+    log::trace!("{:x<200}", "");
+    let mut last = None;
+    for_each(LevelFilter::Trace, |entry| last = Some(*entry));
+    let entry = last.unwrap();
+    assert_eq!(entry.message().len(), MESSAGE_LEN);
+    assert!(entry.message().bytes().all(|byte| byte == b'x'));
+}
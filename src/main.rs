@@ -8,16 +8,15 @@
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use jura_os::task::{executor::Executor, keyboard, Task};
+use jura_os::console::input::{self, Source};
+use jura_os::task::{executor::Executor, Task};
+use jura_os::{print, println};
 use x86_64::{PhysAddr, VirtAddr};
 
 // 型チェックをする
 // _startエントリポイントを定義してくれる => #[no_mangle]も必要なくなる
 entry_point!(kernel_main);
 
-mod serial;
-mod vga_buffer;
-
 extern crate alloc;
 
 // エントリポイント
@@ -27,16 +26,30 @@ extern crate alloc;
 // pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use jura_os::allocator;
-    use jura_os::memory::{self, BootInfoFrameAllocator};
+    use jura_os::memory::{self, GlobalFrameAllocator};
 
     // GDT, IDTなどの初期化
     jura_os::init();
 
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(physical_memory_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+    memory::vmalloc::init();
+    jura_os::gdt::init_stacks();
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap intialization failed");
+    memory::with_mapper(|mapper| allocator::init_heap(mapper, &mut GlobalFrameAllocator))
+        .expect("heap intialization failed");
+    memory::address_space::init();
+
+    if let Err(err) = jura_os::acpi::init() {
+        log::error!("ACPI: {:?}", err);
+    }
+    // 世紀のレジスタの位置はFADTにあるので、ACPIを読んでから時刻の基準を取る
+    jura_os::time::rtc::init();
+    // APICが使えればPICから切り替える
+    jura_os::apic::init();
 
     #[cfg(test)]
     test_main();
@@ -45,7 +58,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     example_mapping(boot_info);
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(keyboard::print_keypress()));
+    executor.spawn(Task::new(input::run_shell(Source::Keyboard)));
+    executor.spawn(Task::new(input::run_shell(Source::Serial)));
     executor.run();
 }
 
@@ -53,7 +67,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // panicした処理には戻らないので、出力先のロックを外して直接書き出す
+    unsafe { jura_os::console::enter_panic_mode() };
     println!("{}", info);
+    print!("{}", jura_os::backtrace::Backtrace::capture());
     jura_os::hlt_loop();
 }
 
@@ -174,21 +191,15 @@ fn address_translation(boot_info: &'static BootInfo) {
 }
 
 #[allow(dead_code)]
-fn example_mapping(boot_info: &'static BootInfo) {
-    use jura_os::memory::{self, init};
-    use x86_64::structures::paging::Page;
-
-    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { init(physical_memory_offset) };
-    let mut frame_allocator = memory::EmptyFrameAllocator;
-
-    // 未使用のページをマップする
-    // containing_addressは指定したアドレスが含まれているページを取得
-    // 0x0が成功するのはレベル1テーブルがすでに存在していたからである
-    let page = Page::containing_address(VirtAddr::new(0x0));
-    memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
-
-    let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
+fn example_mapping(_boot_info: &'static BootInfo) {
+    use jura_os::memory::vmalloc;
+    use x86_64::structures::paging::PageTableFlags;
+
+    // VGAバッファのフレームを、vmallocが選んだ仮想アドレスにマップする
+    let region =
+        unsafe { vmalloc::map_physical(PhysAddr::new(0xb8000), 4096, PageTableFlags::WRITABLE) }
+            .expect("failed to map the VGA buffer");
+    let page_ptr: *mut u64 = region.as_mut_ptr();
 
     // // 0x_f021_f077_f065_f04e = 白背景の“New!“
     // unsafe { page_ptr.offset(400).write_volatile(0x_e021_e077_e065_e04e) };
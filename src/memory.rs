@@ -1,11 +1,160 @@
+use bitmap::BitmapFrameAllocator;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 use x86_64::structures::paging::PhysFrame;
 use x86_64::PhysAddr;
 use x86_64::{
-    structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, Size4KiB},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        Size1GiB, Size2MiB, Size4KiB,
+    },
     VirtAddr,
 };
 
+pub mod address_space;
+pub mod bitmap;
+pub mod cow;
+pub mod region;
+pub mod stack;
+pub mod vmalloc;
+
+// カーネル全体で共有する物理フレームアロケータ
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+/// ブートローダのメモリマップから`FRAME_ALLOCATOR`を初期化する。
+///
+/// この関数はunsafeである：`BitmapFrameAllocator::init`と同じ条件を
+/// 呼び出し元が保証しなければならない。また、一度しか呼び出してはならない。
+pub unsafe fn init_frame_allocator(
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+) {
+    let allocator = BitmapFrameAllocator::init(memory_map, physical_memory_offset);
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+// カーネルのページテーブル
+// ヒープの拡張がこのロックを取るので、ロックを保持したままヒープを使ってはならない
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// 有効なレベル4テーブルから`MAPPER`を初期化する。
+///
+/// この関数はunsafeである：`init`と同じ条件を呼び出し元が保証しなければならない。
+/// `init`と合わせて一度しか呼び出してはならない。
+pub unsafe fn init_mapper(physical_memory_offset: VirtAddr) {
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    *MAPPER.lock() = Some(init(physical_memory_offset));
+    // カーネルからの書き込みも読み込み専用のページで止め、コピーオンライトを働かせる
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+}
+
+// 全物理メモリがマップされている仮想アドレス
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// `init_mapper`に渡された物理メモリのオフセットを返す。
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// 物理アドレスを、全物理メモリをマップした領域の仮想アドレスに変換する。
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// カーネルのレベル4テーブルのフレームを返す。
+pub fn kernel_level_4_frame() -> PhysFrame {
+    with_mapper(|mapper| level_4_frame_of(mapper))
+}
+
+// mapperが使っているレベル4テーブルのフレーム
+fn level_4_frame_of(mapper: &mut OffsetPageTable) -> PhysFrame {
+    let virt = mapper.level_4_table() as *mut PageTable as u64;
+    let phys = virt - physical_memory_offset().as_u64();
+    PhysFrame::containing_address(PhysAddr::new(phys))
+}
+
+/// `addr`を含むレベル4エントリに空のレベル3テーブルを置く。
+///
+/// 既に使われているエントリはそのままにする。
+/// `AddressSpace`はカーネルのレベル4エントリを共有するので、カーネルが使う範囲は
+/// アドレス空間を作る前にこれで用意しておき、以降エントリ自体が変わらないようにする。
+pub fn reserve_level_4_entry(mapper: &mut OffsetPageTable, addr: VirtAddr) {
+    use x86_64::structures::paging::PageTableFlags;
+
+    let entry = &mut mapper.level_4_table()[addr.p4_index()];
+    if !entry.is_unused() {
+        return;
+    }
+    let frame: PhysFrame = GlobalFrameAllocator
+        .allocate_frame()
+        .expect("no frame for a level 3 page table");
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, Size4KiB::SIZE as usize);
+    }
+    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+}
+
+/// `MAPPER`をロックして処理を行う。
+///
+/// 初期化前に呼び出すとpanicする。
+pub fn with_mapper<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut OffsetPageTable<'static>) -> R,
+{
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        f(mapper.as_mut().expect("mapper not initialized"))
+    })
+}
+
+/// `FRAME_ALLOCATOR`をロックして処理を行う。
+///
+/// 初期化前に呼び出すとpanicする。
+pub fn with_frame_allocator<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BitmapFrameAllocator) -> R,
+{
+    // ロック中に割り込みハンドラが同じロックを取ろうとするとデッドロックする
+    interrupts::without_interrupts(|| {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        f(allocator.as_mut().expect("frame allocator not initialized"))
+    })
+}
+
+// `FRAME_ALLOCATOR`を`map_to`などに渡すためのハンドル
+// ロックは呼び出しの間だけ保持される
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        with_frame_allocator(|allocator| allocator.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        with_frame_allocator(|allocator| allocator.deallocate_frame(frame))
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        with_frame_allocator(|allocator| allocator.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        with_frame_allocator(|allocator| allocator.deallocate_frame(frame))
+    }
+}
+
 // 常にNoneを返すFrameAllocator
 pub struct EmptyFrameAllocator;
 unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
@@ -15,6 +164,7 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
 }
 
 // ブートローダのメモリマップから、使用可能なフレームを返すBootInfoFrameAllocator
+// 割り当ての度にnth()で先頭から数え直すのでO(n)であり、解放もできない => BitmapFrameAllocatorを使う
 #[allow(dead_code)]
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
@@ -126,7 +276,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
 
     // L4 -> L3 -> L2 -> L1 -> Physcal Address
     // 複数層のページテールを辿る
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // フレームをページテーブルの参照に変換する
         // 全ての階層でoffsetは必要
         let virt = physical_memory_offset + frame.start_address().as_u64();
@@ -138,8 +288,16 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            // Huge Pageにマップされている場合
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            // Huge Pageにマップされている場合、エントリのアドレスにページ内のオフセットを足す
+            // レベル3のエントリなら1GiB、レベル2のエントリなら2MiBのページ
+            Err(FrameError::HugeFrame) => {
+                let page_size = match level {
+                    1 => Size1GiB::SIZE,
+                    2 => Size2MiB::SIZE,
+                    _ => return None,
+                };
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
         };
     }
 
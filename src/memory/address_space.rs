@@ -0,0 +1,359 @@
+use super::cow::COW;
+use super::region::REGION_START;
+use super::vmalloc::VMALLOC_START;
+use super::{
+    kernel_level_4_frame, phys_to_virt, reserve_level_4_entry, with_frame_allocator, with_mapper,
+    GlobalFrameAllocator,
+};
+use crate::allocator::HEAP_START;
+use conquer_once::spin::OnceCell;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+// AddressSpaceが確保したフレームであることを示す印
+// ページテーブルエントリのbit9-11はOSが自由に使える
+pub const OWNED: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    // カーネルと共有しているレベル4エントリの範囲には触れない
+    KernelRegion,
+    FrameAllocationFailed,
+    // 共有しようとしたページがマップされていない
+    NotMapped,
+    Map(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+    FlagUpdate(FlagUpdateError),
+}
+
+impl From<MapToError<Size4KiB>> for AddressSpaceError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        AddressSpaceError::Map(error)
+    }
+}
+
+impl From<UnmapError> for AddressSpaceError {
+    fn from(error: UnmapError) -> Self {
+        AddressSpaceError::Unmap(error)
+    }
+}
+
+impl From<FlagUpdateError> for AddressSpaceError {
+    fn from(error: FlagUpdateError) -> Self {
+        AddressSpaceError::FlagUpdate(error)
+    }
+}
+
+// カーネルが使うレベル4エントリ (1bitが1エントリ)
+// initで決めた後は変わらず、全てのアドレス空間がこのエントリを共有する
+static KERNEL_SLOTS: OnceCell<[u64; 8]> = OnceCell::uninit();
+
+/// カーネルが使うレベル4エントリを決める。
+///
+/// ヒープ、vmallocと物理領域用の範囲のレベル3テーブルを先に用意し、
+/// それにbootloaderが使っているエントリ（カーネル本体、スタック、物理メモリのマッピング）を
+/// 加えたものをカーネルの範囲とする。以降カーネルはこの範囲の外のレベル4エントリを使わないので、
+/// 共有したエントリが後から変わることはない。
+/// `memory::init_mapper`と`memory::init_frame_allocator`の後、
+/// 最初の`AddressSpace::new`の前に一度だけ呼び出すこと。
+pub fn init() {
+    let slots = with_mapper(|mapper| {
+        for &start in &[HEAP_START as u64, VMALLOC_START, REGION_START] {
+            reserve_level_4_entry(mapper, VirtAddr::new(start));
+        }
+
+        let mut slots = [0u64; 8];
+        for (index, entry) in mapper.level_4_table().iter().enumerate() {
+            if !entry.is_unused() {
+                slots[index / 64] |= 1 << (index % 64);
+            }
+        }
+        slots
+    });
+    KERNEL_SLOTS
+        .try_init_once(|| slots)
+        .expect("address_space::init should only be called once");
+}
+
+/// `addr`がカーネルと共有するレベル4エントリに入っているかを返す。
+///
+/// `init`の前に呼び出すとpanicする。
+pub fn is_kernel_address(addr: VirtAddr) -> bool {
+    is_kernel_slot(usize::from(addr.p4_index()))
+}
+
+fn kernel_slots() -> &'static [u64; 8] {
+    KERNEL_SLOTS
+        .get()
+        .expect("address_space::init has not been called")
+}
+
+fn is_kernel_slot(index: usize) -> bool {
+    kernel_slots()[index / 64] & (1 << (index % 64)) != 0
+}
+
+// プロセスごとのページテーブル
+// カーネルのレベル4エントリを共有し、それ以外の範囲にユーザページをマップする
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// 新しいレベル4テーブルを作り、`init`で決めたカーネルのエントリをコピーする。
+    ///
+    /// bootloaderはカーネルを下位半分に置くので、上位半分に限らず
+    /// カーネルの範囲のエントリを共有する。共有したエントリは`init`以降変わらず、
+    /// その下の変更は全てのアドレス空間に反映される。
+    /// `init`の前に呼び出すとpanicする。
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let slots = kernel_slots();
+        let level_4_frame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+
+        with_mapper(|mapper| {
+            let kernel_table = mapper.level_4_table();
+            let table = unsafe { table_mut(level_4_frame) };
+            table.zero();
+            for (index, entry) in kernel_table.iter().enumerate() {
+                if slots[index / 64] & (1 << (index % 64)) != 0 {
+                    table[index] = entry.clone();
+                } else {
+                    debug_assert!(
+                        entry.is_unused(),
+                        "kernel uses level 4 entry {} outside its range",
+                        index
+                    );
+                }
+            }
+        });
+
+        Ok(AddressSpace { level_4_frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// このアドレス空間が現在CR3に読み込まれているかを返す。
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// CR3を書き換えてこのアドレス空間に切り替える。
+    ///
+    /// この関数はunsafeである：実行中のコードやスタックは共有されたエントリに
+    /// マップされているので切り替え後も有効だが、呼び出し元はこのアドレス空間が
+    /// 使われている間にdropされないことを保証しなければならない。
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    /// カーネルのページテーブルに戻す。
+    pub fn activate_kernel() {
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(kernel_level_4_frame(), flags) };
+    }
+
+    /// 新しいフレームを確保してゼロで埋め、ユーザがアクセスできるページとしてマップする。
+    ///
+    /// 確保したフレームはアンマップ時かdrop時に解放される。
+    pub fn map_user(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, AddressSpaceError> {
+        self.check_user(page)?;
+        let frame: PhysFrame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+        unsafe {
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, Page::<Size4KiB>::SIZE as usize);
+        }
+
+        match unsafe { self.map_user_to(page, frame, flags | OWNED) } {
+            Ok(()) => Ok(frame),
+            Err(error) => {
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                Err(error)
+            }
+        }
+    }
+
+    /// 既存のフレームをユーザがアクセスできるページとしてマップする。
+    ///
+    /// この関数はunsafeである：呼び出し元はフレームを他の用途と
+    /// 競合しないように使うことを保証しなければならない。
+    /// `OWNED`を含めなければフレームはこのアドレス空間では解放されない。
+    pub unsafe fn map_user_to(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        self.check_user(page)?;
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let active = self.is_active();
+        let flush = self.mapper().map_to_with_table_flags(
+            page,
+            frame,
+            flags,
+            table_flags,
+            &mut GlobalFrameAllocator,
+        )?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(())
+    }
+
+    /// ページをアンマップする。`map_user`で確保したフレームなら解放する。
+    pub fn unmap_user(&mut self, page: Page) -> Result<(), AddressSpaceError> {
+        self.check_user(page)?;
+
+        let active = self.is_active();
+        let mut mapper = unsafe { self.mapper() };
+        let owned = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(OWNED),
+            _ => false,
+        };
+        let (frame, flush) = mapper.unmap(page)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+
+        if owned {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+        Ok(())
+    }
+
+    /// `page`のフレームを`target`の`target_page`にもマップし、コピーオンライトで共有する。
+    ///
+    /// 書き込み可能なページは両方のマッピングで読み込み専用になり、`COW`の印が付く。
+    /// どちらかが書き込むとページフォルトハンドラがフレームを複製する。
+    /// `map_user`で確保したフレームなら参照カウントを増やし、両方のアドレス空間が
+    /// フレームを手放したときに解放されるようにする。
+    pub fn share_cow(
+        &mut self,
+        page: Page,
+        target: &mut AddressSpace,
+        target_page: Page,
+    ) -> Result<(), AddressSpaceError> {
+        self.check_user(page)?;
+        target.check_user(target_page)?;
+
+        let active = self.is_active();
+        let mut mapper = unsafe { self.mapper() };
+        let (frame, flags) = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } => (frame, flags),
+            _ => return Err(AddressSpaceError::NotMapped),
+        };
+
+        // 読み込み専用のページはそのまま共有すればよい
+        let shared_flags = if flags.contains(PageTableFlags::WRITABLE) {
+            (flags - PageTableFlags::WRITABLE) | COW
+        } else {
+            flags
+        };
+        let flush = unsafe { mapper.update_flags(page, shared_flags)? };
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+
+        let owned = flags.contains(OWNED);
+        if owned {
+            with_frame_allocator(|allocator| allocator.add_ref(frame));
+        }
+        let result = unsafe { target.map_user_to(target_page, frame, shared_flags) };
+        if result.is_err() && owned {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+        result
+    }
+
+    /// このアドレス空間のページテーブルを操作する`OffsetPageTable`を返す。
+    ///
+    /// この関数はunsafeである：共有しているエントリを書き換えると
+    /// カーネルや他のアドレス空間にも影響する。
+    pub unsafe fn mapper(&mut self) -> OffsetPageTable<'_> {
+        OffsetPageTable::new(
+            table_mut(self.level_4_frame),
+            super::physical_memory_offset(),
+        )
+    }
+
+    fn check_user(&self, page: Page) -> Result<(), AddressSpaceError> {
+        if is_kernel_address(page.start_address()) {
+            Err(AddressSpaceError::KernelRegion)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        let table = unsafe { table_mut(self.level_4_frame) };
+        for (index, entry) in table.iter_mut().enumerate() {
+            if is_kernel_slot(index) || entry.is_unused() {
+                continue;
+            }
+            unsafe { free_table(entry.frame().unwrap(), 3) };
+            entry.set_unused();
+        }
+
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+// フレームに置かれたページテーブルへの参照を返す
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+// levelのテーブルとその下のテーブルを解放する
+// レベル1では`OWNED`の付いたフレームも解放する
+unsafe fn free_table(frame: PhysFrame, level: u8) {
+    let table = table_mut(frame);
+    for entry in table.iter_mut() {
+        if entry.is_unused() {
+            continue;
+        }
+
+        let flags = entry.flags();
+        if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            free_table(entry.frame().unwrap(), level - 1);
+        } else if level == 1 && flags.contains(OWNED) {
+            GlobalFrameAllocator.deallocate_frame(entry.frame().unwrap());
+        }
+        entry.set_unused();
+    }
+
+    GlobalFrameAllocator.deallocate_frame(frame);
+}
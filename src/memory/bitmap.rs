@@ -0,0 +1,290 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::slice;
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+// 1フレームを1bitで管理する物理フレームアロケータ
+// bitが1なら使用中、0なら空き
+// コピーオンライトで共有するため、割り当てたフレームごとに参照カウントを持つ
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // フレームを参照しているマッピングの数。割り当てていないフレームは0
+    ref_counts: &'static mut [u16],
+    // ビットマップが管理するフレーム数 (物理アドレス0から最大のusableアドレスまで)
+    total_frames: usize,
+    // 起動時に使用可能だったフレーム数 (ビットマップ自身の領域は除く)
+    usable_frames: usize,
+    free_frames: usize,
+    // 次に空きを探し始めるワードの位置
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// 渡されたメモリマップからビットマップを作り、FrameAllocatorを初期化する。
+    ///
+    /// ビットマップと参照カウントは最初に見つかった十分な大きさの`USABLE`領域に置かれ、
+    /// `physical_memory_offset`を通してアクセスされる。
+    ///
+    /// この関数はunsafeである：呼び出し元は渡されたメモリマップが有効であり、
+    /// 全物理メモリが`physical_memory_offset`でマップされていることを
+    /// 保証しなければならない。
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let total_frames = (max_addr / FRAME_SIZE) as usize;
+        let words = (total_frames + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_size = (words * 8) as u64;
+        // 参照カウントはビットマップの直後に置く
+        let ref_counts_size = (total_frames * 2) as u64;
+        let bitmap_frames = (bitmap_size + ref_counts_size + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // ビットマップと参照カウントを格納できるusableな領域を探す
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_frames * FRAME_SIZE)
+            .expect("no usable region large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_addr();
+
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, words);
+        // 最初は全て使用中とし、usableな領域だけを空きにする
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        let ref_counts_ptr: *mut u16 =
+            (physical_memory_offset + bitmap_start + bitmap_size).as_mut_ptr();
+        let ref_counts = slice::from_raw_parts_mut(ref_counts_ptr, total_frames);
+        for count in ref_counts.iter_mut() {
+            *count = 0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            ref_counts,
+            total_frames,
+            usable_frames: 0,
+            free_frames: 0,
+            next: 0,
+        };
+
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.mark_free(index);
+            }
+        }
+
+        // ビットマップと参照カウントが置かれているフレームは使用中にする
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        for index in bitmap_first..bitmap_first + bitmap_frames as usize {
+            allocator.mark_used(index);
+        }
+
+        allocator.usable_frames = allocator.free_frames;
+        allocator
+    }
+
+    /// 空いているフレーム数を返す。
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// 使用中のフレーム数を返す。
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// 起動時に使用可能だったフレーム数を返す。
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// フレームを参照しているマッピングの数を返す。割り当てていないフレームなら0を返す。
+    pub fn ref_count(&self, frame: PhysFrame) -> u16 {
+        self.ref_counts[frame_index(frame)]
+    }
+
+    /// 割り当て済みのフレームの参照カウントを1増やす。
+    ///
+    /// フレームを複数のマッピングで共有するときに呼び出す。
+    /// 共有した分だけ`deallocate_frame`を呼び出すと、最後の呼び出しでフレームが解放される。
+    pub fn add_ref(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(
+            self.ref_counts[index] > 0,
+            "frame {:?} is not allocated",
+            frame
+        );
+        self.ref_counts[index] = self.ref_counts[index]
+            .checked_add(1)
+            .expect("frame reference count overflow");
+    }
+
+    /// 物理的に連続した`count`個のフレームを割り当てる。
+    ///
+    /// DMAバッファのように連続した物理メモリが必要な場合に使う。
+    /// 十分な長さの空き領域がなければ`None`を返す。
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        self.allocate_contiguous_aligned(count, 1)
+    }
+
+    /// 先頭が`align`フレームの倍数の位置にある、連続した`count`個のフレームを割り当てる。
+    ///
+    /// `align`は2の累乗でなければならない。Huge Page用のフレームを確保するときに使う。
+    pub fn allocate_contiguous_aligned(
+        &mut self,
+        count: usize,
+        align: usize,
+    ) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two());
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+
+        let mut run_start = 0;
+        let mut run_len = 0;
+        let mut index = 0;
+        while index < self.total_frames {
+            // ワード全体が使用中なら一気に飛ばす
+            if index % BITS_PER_WORD == 0 && self.bitmap[index / BITS_PER_WORD] == !0 {
+                run_len = 0;
+                index += BITS_PER_WORD;
+                continue;
+            }
+
+            if self.is_used(index) {
+                run_len = 0;
+            } else if run_len > 0 || index % align == 0 {
+                // 連続領域はアラインされた位置からしか始めない
+                if run_len == 0 {
+                    run_start = index;
+                }
+                run_len += 1;
+                if run_len == count {
+                    for i in run_start..run_start + count {
+                        self.mark_used(i);
+                        self.ref_counts[i] = 1;
+                    }
+                    return Some(PhysFrame::range(
+                        frame_from_index(run_start),
+                        frame_from_index(run_start + count),
+                    ));
+                }
+            }
+            index += 1;
+        }
+
+        None
+    }
+
+    /// `allocate_contiguous`で割り当てたフレームをまとめて解放する。
+    ///
+    /// この関数はunsafeである：呼び出し元は解放するフレームが
+    /// もう使われていないことを保証しなければならない。
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        if !self.is_used(index) {
+            self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+            self.free_frames -= 1;
+        }
+    }
+
+    fn mark_free(&mut self, index: usize) {
+        if self.is_used(index) {
+            self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+            self.free_frames += 1;
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let words = self.bitmap.len();
+        for i in 0..words {
+            let word_index = (self.next + i) % words;
+            let word = self.bitmap[word_index];
+            if word != !0 {
+                // 最下位の空きbitを探す
+                let index = word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+                self.mark_used(index);
+                self.ref_counts[index] = 1;
+                self.next = word_index;
+                return Some(frame_from_index(index));
+            }
+        }
+
+        None
+    }
+}
+
+// 参照カウントを1減らし、0になったらフレームを解放する
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame);
+        assert!(index < self.total_frames, "frame {:?} out of range", frame);
+        // 使用中でも参照カウントが0のフレーム (ビットマップ自身の領域やusableでない領域) は
+        // このアロケータが割り当てたものではないので、解放するとused_framesが狂う
+        assert!(
+            self.ref_counts[index] > 0,
+            "frame {:?} is not allocated (double free?)",
+            frame
+        );
+        debug_assert!(self.is_used(index));
+
+        self.ref_counts[index] -= 1;
+        if self.ref_counts[index] > 0 {
+            return;
+        }
+        self.mark_free(index);
+        // 解放されたフレームから探し始めると空きがすぐに見つかる
+        self.next = self.next.min(index / BITS_PER_WORD);
+    }
+}
+
+// 2MiBのフレームは連続した512個の4KiBフレームとして管理する
+const FRAMES_PER_2MIB: usize = (Size2MiB::SIZE / FRAME_SIZE) as usize;
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let range = self.allocate_contiguous_aligned(FRAMES_PER_2MIB, FRAMES_PER_2MIB)?;
+        Some(PhysFrame::containing_address(range.start.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_contiguous(PhysFrame::range(start, start + FRAMES_PER_2MIB as u64));
+    }
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+fn frame_from_index(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
@@ -0,0 +1,123 @@
+// コピーオンライト
+// 共有したページは読み込み専用にして`COW`の印を付け、書き込みのページフォルトで複製する
+
+use super::address_space::OWNED;
+use super::bitmap::BitmapFrameAllocator;
+use super::{level_4_frame_of, phys_to_virt, physical_memory_offset, FRAME_ALLOCATOR, MAPPER};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+// 書き込まれたら複製するページであることを示す印
+// bit9は`OWNED`が使っている
+pub const COW: PageTableFlags = PageTableFlags::BIT_10;
+
+/// `addr`への書き込みで起きたページフォルトがコピーオンライトのページのものなら、
+/// ページを書き込めるようにして`true`を返す。
+///
+/// 他にフレームを共有しているマッピングがあれば新しいフレームに中身を複製し、
+/// 現在のページテーブルのマッピングだけを差し替える。最後の一つなら複製せずに
+/// 書き込みを許可する。ページフォルトハンドラから呼ばれるので、ページテーブルや
+/// フレームアロケータを操作中でロックが取れなければ処理せずに`false`を返す。
+pub fn handle_write_fault(addr: VirtAddr) -> bool {
+    // カーネルのページテーブルとの競合を避けるため、常にMAPPERのロックを取る
+    let mut kernel_mapper = match MAPPER.try_lock() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    let kernel_mapper = match kernel_mapper.as_mut() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    let mut frames = match FRAME_ALLOCATOR.try_lock() {
+        Some(frames) => frames,
+        None => return false,
+    };
+    let frames = match frames.as_mut() {
+        Some(frames) => frames,
+        None => return false,
+    };
+
+    // プロセスのアドレス空間で起きた場合は、そのページテーブルを操作する
+    let (level_4_frame, _) = Cr3::read();
+    if level_4_frame == level_4_frame_of(kernel_mapper) {
+        copy_on_write(kernel_mapper, frames, addr)
+    } else {
+        let table: &mut PageTable =
+            unsafe { &mut *phys_to_virt(level_4_frame.start_address()).as_mut_ptr() };
+        let mut mapper = unsafe { OffsetPageTable::new(table, physical_memory_offset()) };
+        copy_on_write(&mut mapper, frames, addr)
+    }
+}
+
+fn copy_on_write(
+    mapper: &mut OffsetPageTable,
+    frames: &mut BitmapFrameAllocator,
+    addr: VirtAddr,
+) -> bool {
+    let (frame, flags) = match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        _ => return false,
+    };
+    if !flags.contains(COW) {
+        return false;
+    }
+
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let owned = flags.contains(OWNED);
+    let new_flags = (flags - COW) | PageTableFlags::WRITABLE;
+
+    // 共有している相手がもういなければ、そのまま書き込めるようにする
+    if owned && frames.ref_count(frame) == 1 {
+        return match unsafe { mapper.update_flags(page, new_flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        };
+    }
+
+    let new_frame: PhysFrame = match frames.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+            phys_to_virt(new_frame.start_address()).as_mut_ptr::<u8>(),
+            Page::<Size4KiB>::SIZE as usize,
+        );
+    }
+
+    match mapper.unmap(page) {
+        Ok((_, flush)) => flush.flush(),
+        Err(_) => {
+            unsafe { frames.deallocate_frame(new_frame) };
+            return false;
+        }
+    }
+    // 複製したフレームはこのマッピングが持つ
+    // アンマップした直後なので、ページテーブルのフレームは新しく必要にならない
+    let new_flags = new_flags | OWNED;
+    unsafe {
+        mapper
+            .map_to(page, new_frame, new_flags, frames)
+            .expect("failed to remap a copy-on-write page")
+            .flush();
+    }
+
+    // 元のフレームの参照を一つ手放す
+    if owned {
+        unsafe { frames.deallocate_frame(frame) };
+    }
+    true
+}
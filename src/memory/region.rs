@@ -0,0 +1,213 @@
+// 大きな領域をまとめてマップする
+// アラインメントが許す範囲ではHuge Page (2MiB, 1GiB) を使い、ページテーブルとTLBの消費を抑える
+
+use super::phys_to_virt;
+use core::arch::x86_64::__cpuid;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+    PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// カーネルが`map_physical_region`で物理領域をマップするための範囲 (レベル4エントリ1つ分)
+// `AddressSpace`と共有するため、この範囲にはユーザページを置かない
+pub const REGION_START: u64 = 0x0000_5000_0000_0000;
+pub const REGION_END: u64 = REGION_START + (1 << 39);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapRegionError {
+    // アドレスかサイズが4KiBの倍数ではない
+    Unaligned,
+    FrameAllocationFailed,
+    // 途中のエントリが既にHuge Pageとして使われている
+    ParentEntryHugePage,
+    PageAlreadyMapped,
+}
+
+impl<S: PageSize> From<MapToError<S>> for MapRegionError {
+    fn from(error: MapToError<S>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => MapRegionError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MapRegionError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(_) => MapRegionError::PageAlreadyMapped,
+        }
+    }
+}
+
+/// CPUが1GiBのページに対応しているかを返す。
+///
+/// CPUID 0x80000001のEDXのbit26 (Page1GB) を見る。
+/// QEMUの既定のCPUなど、対応していない環境も多い。
+pub fn supports_1gib_pages() -> bool {
+    #[allow(unused_unsafe)]
+    let result = unsafe { __cpuid(0x8000_0000) };
+    if result.eax < 0x8000_0001 {
+        return false;
+    }
+    #[allow(unused_unsafe)]
+    let result = unsafe { __cpuid(0x8000_0001) };
+    result.edx & (1 << 26) != 0
+}
+
+/// `[phys, phys + size)`の物理メモリを`virt`から始まる仮想アドレスにマップする。
+///
+/// フレームバッファやMMIOの窓のように、物理アドレスが決まっている領域に使う。
+/// 仮想アドレスと物理アドレスが両方とも1GiBか2MiBでアラインされている部分は
+/// Huge Pageでマップし、残りは4KiBのページでマップする。
+/// 途中で失敗した場合、それまでにマップしたページはそのまま残る。
+///
+/// この関数はunsafeである：呼び出し元は物理メモリを別名でマップしても
+/// 問題がないことを保証しなければならない。
+pub unsafe fn map_physical_region(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapRegionError> {
+    if !virt.is_aligned(Size4KiB::SIZE)
+        || !phys.is_aligned(Size4KiB::SIZE)
+        || size % Size4KiB::SIZE != 0
+    {
+        return Err(MapRegionError::Unaligned);
+    }
+
+    let flags = flags | PageTableFlags::PRESENT;
+    let use_1gib = supports_1gib_pages();
+    let mut offset = 0;
+    while offset < size {
+        let (virt, phys, remaining) = (virt + offset, phys + offset, size - offset);
+
+        let step = if use_1gib && fits::<Size1GiB>(virt, phys, remaining) {
+            let page = Page::<Size1GiB>::containing_address(virt);
+            let frame = PhysFrame::<Size1GiB>::containing_address(phys);
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            Size1GiB::SIZE
+        } else if fits::<Size2MiB>(virt, phys, remaining) {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::<Size2MiB>::containing_address(phys);
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            Size2MiB::SIZE
+        } else {
+            let page = Page::<Size4KiB>::containing_address(virt);
+            let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            Size4KiB::SIZE
+        };
+        offset += step;
+    }
+
+    Ok(())
+}
+
+/// `virt`から`size`バイトを新しくゼロで埋めたフレームにマップする。
+///
+/// 2MiBでアラインされた部分は2MiBのフレームでマップするが、
+/// 連続した2MiBのフレームが見つからなければ4KiBのフレームで埋める。
+/// 途中で失敗した場合、それまでにマップしたページはそのまま残る。
+pub fn map_anonymous<A>(
+    virt: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut A,
+) -> Result<(), MapRegionError>
+where
+    A: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>,
+{
+    if !virt.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+        return Err(MapRegionError::Unaligned);
+    }
+
+    let mut offset = 0;
+    while offset < size {
+        let virt = virt + offset;
+        let remaining = size - offset;
+
+        if virt.is_aligned(Size2MiB::SIZE) && remaining >= Size2MiB::SIZE {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            match map_anonymous_huge(page, flags, mapper, frame_allocator) {
+                Ok(()) => {
+                    offset += Size2MiB::SIZE;
+                    continue;
+                }
+                Err(MapRegionError::FrameAllocationFailed) => {}
+                Err(error) => return Err(error),
+            }
+        }
+
+        let page = Page::<Size4KiB>::containing_address(virt);
+        let frame = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+            .ok_or(MapRegionError::FrameAllocationFailed)?;
+        map_zeroed(page, frame, flags, mapper, frame_allocator)?;
+        offset += Size4KiB::SIZE;
+    }
+
+    Ok(())
+}
+
+/// 2MiBのフレームを確保してゼロで埋め、`page`にマップする。
+///
+/// 連続した2MiBのフレームが確保できないなどで失敗した場合は、
+/// 何もマップせずにエラーを返す。
+pub fn map_anonymous_huge<A>(
+    page: Page<Size2MiB>,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut A,
+) -> Result<(), MapRegionError>
+where
+    A: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>,
+{
+    let frame = FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator)
+        .ok_or(MapRegionError::FrameAllocationFailed)?;
+    map_zeroed(page, frame, flags, mapper, frame_allocator)
+}
+
+// フレームをゼロで埋めてマップする。失敗したらフレームを解放する
+fn map_zeroed<S, A>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut A,
+) -> Result<(), MapRegionError>
+where
+    S: PageSize,
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<S>,
+{
+    zero_frame(frame);
+    let flags = flags | PageTableFlags::PRESENT;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(error) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(error.into())
+        }
+    }
+}
+
+// virtとphysが両方ともSでアラインされ、残りがSの大きさ以上あるか
+fn fits<S: PageSize>(virt: VirtAddr, phys: PhysAddr, remaining: u64) -> bool {
+    virt.is_aligned(S::SIZE) && phys.is_aligned(S::SIZE) && remaining >= S::SIZE
+}
+
+fn zero_frame<S: PageSize>(frame: PhysFrame<S>) {
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, S::SIZE as usize);
+    }
+}
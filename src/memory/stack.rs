@@ -0,0 +1,124 @@
+// ガードページ付きのカーネルスタック
+// スタックはvmallocから確保し、直下のマップしないページに触れたらスタックオーバーフローとみなす
+
+use super::vmalloc::{self, VmRegion, VmallocError};
+use core::arch::asm;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+// スタックの直下に置くガードページの数
+const GUARD_PAGES: usize = 1;
+
+const PAGE_SIZE: u64 = 4096;
+
+// 同時に登録できるスタックの数
+const MAX_STACKS: usize = 32;
+
+// 登録されたスタックのガードページの範囲と名前
+#[derive(Clone, Copy)]
+struct StackEntry {
+    guard_start: u64,
+    bottom: u64,
+    name: &'static str,
+}
+
+static STACKS: Mutex<[Option<StackEntry>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+pub struct KernelStack {
+    region: VmRegion,
+    name: &'static str,
+}
+
+impl KernelStack {
+    /// `pages`ページのスタックを確保し、ガードページを登録する。
+    ///
+    /// `name`はスタックオーバーフローを報告するときに表示される。
+    pub fn new(name: &'static str, pages: usize) -> Result<Self, VmallocError> {
+        let region = vmalloc::vmalloc_guarded(
+            pages * PAGE_SIZE as usize,
+            GUARD_PAGES,
+            PageTableFlags::WRITABLE,
+        )?;
+        let entry = StackEntry {
+            guard_start: region.start().as_u64() - GUARD_PAGES as u64 * PAGE_SIZE,
+            bottom: region.start().as_u64(),
+            name,
+        };
+
+        let registered = interrupts::without_interrupts(|| {
+            let mut stacks = STACKS.lock();
+            match stacks.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => {
+                    *slot = Some(entry);
+                    true
+                }
+                None => false,
+            }
+        });
+        if !registered {
+            vmalloc::vfree(region)?;
+            return Err(VmallocError::TooManyAreas);
+        }
+
+        Ok(KernelStack { region, name })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// スタックの先頭 (一番高いアドレス) を返す。スタックはここから下に伸びる。
+    pub fn top(&self) -> VirtAddr {
+        self.region.end()
+    }
+
+    /// スタックの底 (一番低い使えるアドレス) を返す。
+    pub fn bottom(&self) -> VirtAddr {
+        self.region.start()
+    }
+
+    /// スタックポインタをこのスタックの先頭に切り替えて`entry`を呼び出す。
+    ///
+    /// この関数はunsafeである：切り替える前のスタックには戻らないので、
+    /// 呼び出し元はスタック上の値が不要になっていることを保証しなければならない。
+    /// また、このスタックはdropされずに残り続けなければならない。
+    pub unsafe fn switch_to(&self, entry: extern "C" fn() -> !) -> ! {
+        asm!(
+            "mov rsp, {top}",
+            "call {entry}",
+            top = in(reg) self.top().as_u64(),
+            entry = in(reg) entry as usize,
+            options(noreturn)
+        );
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let bottom = self.bottom().as_u64();
+        interrupts::without_interrupts(|| {
+            let mut stacks = STACKS.lock();
+            for slot in stacks.iter_mut() {
+                if matches!(slot, Some(entry) if entry.bottom == bottom) {
+                    *slot = None;
+                }
+            }
+        });
+        vmalloc::vfree(self.region).expect("failed to free a kernel stack");
+    }
+}
+
+/// `addr`が登録されたスタックのガードページにあれば、そのスタックの名前を返す。
+///
+/// ページフォルトハンドラから呼ばれるので、ロックが取れなければ`None`を返す。
+pub fn overflowed_stack(addr: VirtAddr) -> Option<&'static str> {
+    let stacks = STACKS.try_lock()?;
+    let addr = addr.as_u64();
+    stacks
+        .iter()
+        .flatten()
+        .find(|entry| entry.guard_start <= addr && addr < entry.bottom)
+        .map(|entry| entry.name)
+}
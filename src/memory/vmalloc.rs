@@ -0,0 +1,443 @@
+// カーネル用の仮想アドレスを管理する (vmalloc)
+// レベル4エントリ1つ分の範囲から重ならない領域を切り出し、ページをマップして貸し出す
+// 管理表は固定長の配列なので、ヒープの初期化前でも使える
+
+use super::{
+    phys_to_virt, reserve_level_4_entry, with_mapper, GlobalFrameAllocator, FRAME_ALLOCATOR, MAPPER,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// vmallocが使う仮想アドレスの範囲 (レベル4エントリ1つ分 = 512GiB)
+pub const VMALLOC_START: u64 = 0x0000_6000_0000_0000;
+pub const VMALLOC_END: u64 = VMALLOC_START + (1 << 39);
+
+// 同時に貸し出せる領域の数
+const MAX_AREAS: usize = 128;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug)]
+pub enum VmallocError {
+    NotInitialized,
+    // サイズが0
+    InvalidSize,
+    OutOfAddressSpace,
+    TooManyAreas,
+    FrameAllocationFailed,
+    Map(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+    // vmallocが貸し出した領域ではない
+    NotAllocated,
+}
+
+impl From<MapToError<Size4KiB>> for VmallocError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        VmallocError::Map(error)
+    }
+}
+
+impl From<UnmapError> for VmallocError {
+    fn from(error: UnmapError) -> Self {
+        VmallocError::Unmap(error)
+    }
+}
+
+/// vmallocが貸し出した領域。ガードページは含まない。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmRegion {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl VmRegion {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// 領域の終端 (この値は含まない) を返す。
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.start.as_mut_ptr()
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AreaKind {
+    // vmallocが確保したフレーム。解放時にフレームも返す
+    Anonymous,
+    // 既存の物理メモリ (MMIOなど)。解放時にフレームは返さない
+    Physical,
+    // 予約だけしておき、触れられたページからページフォルトでマップする
+    // 確保したフレームは解放時に返す
+    Lazy(PageTableFlags),
+}
+
+impl AreaKind {
+    // 解放時にフレームを返すか
+    fn owns_frames(self) -> bool {
+        match self {
+            AreaKind::Anonymous | AreaKind::Lazy(_) => true,
+            AreaKind::Physical => false,
+        }
+    }
+}
+
+// 予約した仮想アドレスの範囲
+// [start, start + guard) と末尾のguardページはマップしない
+#[derive(Debug, Clone, Copy)]
+struct Area {
+    start: u64,
+    pages: u64,
+    guard_pages: u64,
+    kind: AreaKind,
+}
+
+impl Area {
+    const EMPTY: Area = Area {
+        start: 0,
+        pages: 0,
+        guard_pages: 0,
+        kind: AreaKind::Anonymous,
+    };
+
+    // マップされる範囲の先頭
+    fn mapped_start(&self) -> u64 {
+        self.start + self.guard_pages * PAGE_SIZE
+    }
+
+    fn mapped_end(&self) -> u64 {
+        self.mapped_start() + self.pages * PAGE_SIZE
+    }
+
+    // 末尾のガードページも含めた終端
+    fn end(&self) -> u64 {
+        self.mapped_end() + self.guard_pages * PAGE_SIZE
+    }
+
+    fn mapped_pages(&self) -> impl Iterator<Item = Page> {
+        let start = Page::containing_address(VirtAddr::new(self.mapped_start()));
+        Page::range(start, start + self.pages)
+    }
+}
+
+// 開始アドレス順に並んだ予約済みの範囲
+struct Vmalloc {
+    areas: [Area; MAX_AREAS],
+    count: usize,
+    initialized: bool,
+}
+
+impl Vmalloc {
+    const fn new() -> Self {
+        Vmalloc {
+            areas: [Area::EMPTY; MAX_AREAS],
+            count: 0,
+            initialized: false,
+        }
+    }
+
+    /// 前後のガードページを含めて入る最初の隙間を探し、予約する。
+    ///
+    /// 予約した範囲の`areas`でのインデックスを返す。
+    fn reserve(
+        &mut self,
+        pages: u64,
+        guard_pages: u64,
+        kind: AreaKind,
+    ) -> Result<usize, VmallocError> {
+        if !self.initialized {
+            return Err(VmallocError::NotInitialized);
+        }
+        if pages == 0 {
+            return Err(VmallocError::InvalidSize);
+        }
+        if self.count == MAX_AREAS {
+            return Err(VmallocError::TooManyAreas);
+        }
+
+        let span = (pages + 2 * guard_pages) * PAGE_SIZE;
+        let mut candidate = VMALLOC_START;
+        let mut index = 0;
+        while index < self.count && self.areas[index].start - candidate < span {
+            candidate = self.areas[index].end();
+            index += 1;
+        }
+        if VMALLOC_END - candidate < span {
+            return Err(VmallocError::OutOfAddressSpace);
+        }
+
+        // 後ろの要素をずらして挿入する
+        self.areas.copy_within(index..self.count, index + 1);
+        self.areas[index] = Area {
+            start: candidate,
+            pages,
+            guard_pages,
+            kind,
+        };
+        self.count += 1;
+        Ok(index)
+    }
+
+    fn remove(&mut self, index: usize) -> Area {
+        let area = self.areas[index];
+        self.areas.copy_within(index + 1..self.count, index);
+        self.count -= 1;
+        area
+    }
+
+    // addrをマップされる範囲に含む予約のインデックス
+    fn find(&self, addr: u64) -> Option<usize> {
+        self.areas[..self.count]
+            .iter()
+            .position(|area| area.mapped_start() <= addr && addr < area.mapped_end())
+    }
+}
+
+// ロックの順序は VMALLOC -> MAPPER -> FRAME_ALLOCATOR
+static VMALLOC: Mutex<Vmalloc> = Mutex::new(Vmalloc::new());
+
+fn with_vmalloc<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Vmalloc) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut VMALLOC.lock()))
+}
+
+/// vmallocの範囲を受け持つレベル3テーブルを作る。
+///
+/// このエントリは`address_space::init`でも用意されるので、全てのアドレス空間から
+/// vmallocの領域が見える。
+/// `memory::init_mapper`と`memory::init_frame_allocator`の後に呼び出さなければならない。
+pub fn init() {
+    with_vmalloc(|vmalloc| {
+        with_mapper(|mapper| reserve_level_4_entry(mapper, VirtAddr::new(VMALLOC_START)));
+        vmalloc.initialized = true;
+    });
+}
+
+/// `size`バイトの領域を確保し、新しくゼロで埋めたフレームを`flags`でマップする。
+///
+/// 領域の前後には1ページずつガードページを置く。
+pub fn vmalloc(size: usize, flags: PageTableFlags) -> Result<VmRegion, VmallocError> {
+    vmalloc_guarded(size, 1, flags)
+}
+
+/// 前後に`guard_pages`ページずつマップしないページを置いて`size`バイトの領域を確保する。
+///
+/// ガードページに触れるとページフォルトが発生するので、スタックやバッファの
+/// はみ出しを検出できる。
+pub fn vmalloc_guarded(
+    size: usize,
+    guard_pages: usize,
+    flags: PageTableFlags,
+) -> Result<VmRegion, VmallocError> {
+    let pages = pages_for(size as u64);
+    with_vmalloc(|vmalloc| {
+        let index = vmalloc.reserve(pages, guard_pages as u64, AreaKind::Anonymous)?;
+        let area = vmalloc.areas[index];
+
+        let result = with_mapper(|mapper| -> Result<(), VmallocError> {
+            for page in area.mapped_pages() {
+                let frame: PhysFrame = GlobalFrameAllocator
+                    .allocate_frame()
+                    .ok_or(VmallocError::FrameAllocationFailed)?;
+                zero_frame(frame);
+                let flags = flags | PageTableFlags::PRESENT;
+                match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+                    Ok(flush) => flush.flush(),
+                    Err(error) => {
+                        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                        return Err(error.into());
+                    }
+                }
+            }
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            // マップできた分を戻してから予約を取り消す
+            unmap_area(&area);
+            vmalloc.remove(index);
+            return Err(error);
+        }
+
+        Ok(VmRegion {
+            start: VirtAddr::new(area.mapped_start()),
+            size: pages * PAGE_SIZE,
+        })
+    })
+}
+
+/// `[phys, phys + size)`の物理メモリを新しい仮想アドレスにマップする。
+///
+/// デバイスのレジスタやフレームバッファに使う。`phys`がページの途中なら、
+/// 返される領域もページの途中から始まる。解放してもフレームは返さない。
+///
+/// この関数はunsafeである：呼び出し元は物理メモリを別名でマップしても
+/// 問題がないことを保証しなければならない。
+pub unsafe fn map_physical(
+    phys: PhysAddr,
+    size: usize,
+    flags: PageTableFlags,
+) -> Result<VmRegion, VmallocError> {
+    let offset = phys.as_u64() % PAGE_SIZE;
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let pages = pages_for(offset + size as u64);
+    with_vmalloc(|vmalloc| {
+        let index = vmalloc.reserve(pages, 1, AreaKind::Physical)?;
+        let area = vmalloc.areas[index];
+
+        let result = with_mapper(|mapper| -> Result<(), VmallocError> {
+            for (i, page) in area.mapped_pages().enumerate() {
+                let frame = first_frame + i as u64;
+                let flags = flags | PageTableFlags::PRESENT;
+                mapper
+                    .map_to(page, frame, flags, &mut GlobalFrameAllocator)?
+                    .flush();
+            }
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            unmap_area(&area);
+            vmalloc.remove(index);
+            return Err(error);
+        }
+
+        Ok(VmRegion {
+            start: VirtAddr::new(area.mapped_start() + offset),
+            size: size as u64,
+        })
+    })
+}
+
+/// `size`バイトの領域を予約だけして、ページはマップせずに返す。
+///
+/// 領域内のページに初めて触れたときにページフォルトが発生し、
+/// `handle_page_fault`がゼロで埋めたフレームを`flags`でマップする。
+/// 大きなバッファのうち実際に使った分だけ物理メモリを消費させたいときに使う。
+pub fn vmalloc_lazy(size: usize, flags: PageTableFlags) -> Result<VmRegion, VmallocError> {
+    let pages = pages_for(size as u64);
+    with_vmalloc(|vmalloc| {
+        let index = vmalloc.reserve(pages, 1, AreaKind::Lazy(flags))?;
+        let area = vmalloc.areas[index];
+        Ok(VmRegion {
+            start: VirtAddr::new(area.mapped_start()),
+            size: pages * PAGE_SIZE,
+        })
+    })
+}
+
+/// `addr`へのアクセスで起きたページフォルトが遅延マップの領域のものなら、
+/// フレームを割り当ててマップし`true`を返す。
+///
+/// ページフォルトハンドラから呼ばれる。フォルトがvmallocやページテーブル、
+/// フレームアロケータの操作中に起きた場合はロックが取れないので、処理せずに`false`を返す。
+pub fn handle_page_fault(addr: VirtAddr) -> bool {
+    let vmalloc = match VMALLOC.try_lock() {
+        Some(vmalloc) => vmalloc,
+        None => return false,
+    };
+    let flags = match vmalloc.find(addr.as_u64()).map(|i| vmalloc.areas[i].kind) {
+        Some(AreaKind::Lazy(flags)) => flags,
+        _ => return false,
+    };
+    let mut mapper = match MAPPER.try_lock() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    let mapper = match mapper.as_mut() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    let mut frames = match FRAME_ALLOCATOR.try_lock() {
+        Some(frames) => frames,
+        None => return false,
+    };
+    let frames = match frames.as_mut() {
+        Some(frames) => frames,
+        None => return false,
+    };
+
+    let frame: PhysFrame = match frames.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    zero_frame(frame);
+    let page = Page::containing_address(addr);
+    let flags = flags | PageTableFlags::PRESENT;
+    match unsafe { mapper.map_to(page, frame, flags, frames) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { frames.deallocate_frame(frame) };
+            false
+        }
+    }
+}
+
+/// `vmalloc`や`map_physical`で得た領域をアンマップし、仮想アドレスを返す。
+///
+/// `vmalloc`や`vmalloc_lazy`で確保したフレームは解放される。
+pub fn vfree(region: VmRegion) -> Result<(), VmallocError> {
+    with_vmalloc(|vmalloc| {
+        let index = vmalloc
+            .find(region.start.as_u64())
+            .ok_or(VmallocError::NotAllocated)?;
+        let area = vmalloc.remove(index);
+        unmap_area(&area);
+        Ok(())
+    })
+}
+
+// 範囲内のマップされているページを全てアンマップする
+// フレームを持つ領域ならフレームも解放する
+fn unmap_area(area: &Area) {
+    with_mapper(|mapper| {
+        for page in area.mapped_pages() {
+            let frame = match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    frame
+                }
+                // 途中までしかマップできなかった場合や、遅延マップでまだ触れていないページ
+                Err(UnmapError::PageNotMapped) => continue,
+                Err(error) => panic!("failed to unmap vmalloc page {:?}: {:?}", page, error),
+            };
+            if area.kind.owns_frames() {
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            }
+        }
+    });
+}
+
+fn pages_for(size: u64) -> u64 {
+    (size + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+fn zero_frame(frame: PhysFrame) {
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, PAGE_SIZE as usize);
+    }
+}
@@ -1,20 +1,58 @@
-use core::fmt::Write;
+use core::fmt::{self, Write};
 
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
 use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// 最初のシリアルインターフェースの標準のポート番号
+pub const COM1: u16 = 0x3F8;
+
+// Line Status Registerのオフセットと、受信データありのbit
+const LINE_STATUS: u16 = 5;
+const DATA_READY: u8 = 1 << 0;
 
 // lazy_staticを使用することによってinitメソッドが初回使用時のみ呼び出される
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        // 0x3F8は最初のシリアルインターフェースの標準のポート番号
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
+        // 受信データありの割り込みも有効になる
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// 受信したバイトがあれば読み出す。
+///
+/// 割り込みハンドラから呼べるよう、`SERIAL1`をロックせずにポートを直接読む。
+pub fn try_read_byte() -> Option<u8> {
+    let mut line_status: Port<u8> = Port::new(COM1 + LINE_STATUS);
+    let mut data: Port<u8> = Port::new(COM1);
+    unsafe {
+        if line_status.read() & DATA_READY != 0 {
+            Some(data.read())
+        } else {
+            None
+        }
+    }
+}
+
+/// シリアルポートへの書き込み。端末で行頭に戻るよう"\n"を"\r\n"に変える。
+pub struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                _print(format_args!("\r\n"));
+            }
+            _print(format_args!("{}", line));
+        }
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     // Mutexがロックしている間は割り込みが発生しないことを保証する
@@ -0,0 +1,298 @@
+// キーボードやシリアルポートから入力された1行のコマンドを実行する
+
+use crate::acpi::{self, signature_str};
+use crate::allocator::{self, stats};
+use crate::backtrace;
+use crate::interrupts::irq;
+use crate::logger;
+use crate::time;
+use core::fmt::{self, Write};
+use log::LevelFilter;
+
+/// 入力された1行を解釈して実行し、結果を`out`に書き出す。
+///
+/// 知らないコマンドはそのまま表示する。
+pub fn execute_on(line: &str, out: &mut dyn Write) -> fmt::Result {
+    let mut args = line.split_whitespace();
+    let command = match args.next() {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    match command {
+        "acpi" => acpi(out),
+        "date" => date(out),
+        "dmesg" => dmesg(out, args.next()),
+        "help" => help(out),
+        "heap" => heap(out, args),
+        "irq" => irqs(out),
+        "uptime" => uptime(out),
+        _ => writeln!(out, "{}", line),
+    }
+}
+
+fn help(out: &mut dyn Write) -> fmt::Result {
+    writeln!(
+        out,
+        "acpi               show ACPI tables, CPUs and interrupt controllers"
+    )?;
+    writeln!(out, "date               show the current date and time")?;
+    writeln!(
+        out,
+        "dmesg [level]      show kernel logs (error|warn|info|debug|trace)"
+    )?;
+    writeln!(out, "help               show this message")?;
+    writeln!(out, "heap               show heap usage")?;
+    writeln!(out, "heap track on|off  record live allocations")?;
+    writeln!(
+        out,
+        "heap leaks         list recorded allocations not yet freed"
+    )?;
+    writeln!(out, "irq                show interrupt counts and handlers")?;
+    writeln!(out, "uptime             show time since boot")
+}
+
+fn acpi(out: &mut dyn Write) -> fmt::Result {
+    let info = match acpi::info() {
+        Some(info) => info,
+        None => {
+            return writeln!(out, "ACPI tables not available");
+        }
+    };
+
+    writeln!(
+        out,
+        "ACPI revision {} OEM {}",
+        info.revision,
+        core::str::from_utf8(&info.oem_id).unwrap_or("?")
+    )?;
+    for table in info.tables.iter() {
+        writeln!(
+            out,
+            "  {} at {:#x} ({} bytes)",
+            signature_str(&table.signature),
+            table.address.as_u64(),
+            table.length
+        )?;
+    }
+
+    writeln!(
+        out,
+        "local APIC at {:#x}, {} CPUs",
+        info.local_apic_address,
+        info.cpu_count()
+    )?;
+    for cpu in info.processors.iter() {
+        writeln!(
+            out,
+            "  cpu {} apic id {}{}",
+            cpu.processor_id,
+            cpu.apic_id,
+            if cpu.enabled { "" } else { " (disabled)" }
+        )?;
+    }
+    for io_apic in info.io_apics.iter() {
+        writeln!(
+            out,
+            "IO-APIC {} at {:#x}, GSI base {}",
+            io_apic.id, io_apic.address, io_apic.gsi_base
+        )?;
+    }
+    for entry in info.interrupt_overrides.iter() {
+        writeln!(
+            out,
+            "  IRQ {} -> GSI {} {:?} {:?}",
+            entry.irq, entry.gsi, entry.polarity, entry.trigger
+        )?;
+    }
+
+    if let Some(fadt) = info.fadt {
+        writeln!(
+            out,
+            "FADT: SCI {} SMI {:#x} PM1a evt {:#x} cnt {:#x} PM1b evt {:#x} cnt {:#x} PM timer {:#x}",
+            fadt.sci_interrupt,
+            fadt.smi_command_port,
+            fadt.pm1a_event_block,
+            fadt.pm1a_control_block,
+            fadt.pm1b_event_block,
+            fadt.pm1b_control_block,
+            fadt.pm_timer_block
+        )?;
+        if let Some((register, value)) = fadt.reset {
+            writeln!(
+                out,
+                "  reset: write {:#x} to {:#x} (space {})",
+                value, register.address, register.address_space
+            )?;
+        }
+    }
+    if let Some(hpet) = info.hpet {
+        writeln!(
+            out,
+            "HPET {} at {:#x}, {} comparators, {}-bit counter, min tick {}",
+            hpet.hpet_number,
+            hpet.base_address,
+            hpet.comparators,
+            if hpet.counter_64bit { 64 } else { 32 },
+            hpet.minimum_tick
+        )?;
+    }
+    Ok(())
+}
+
+fn date(out: &mut dyn Write) -> fmt::Result {
+    let timestamp = time::rtc::unix_timestamp();
+    writeln!(
+        out,
+        "{} ({})",
+        time::rtc::DateTime::from_unix_timestamp(timestamp),
+        timestamp
+    )
+}
+
+fn dmesg(out: &mut dyn Write, level: Option<&str>) -> fmt::Result {
+    let level = match level.map(str::parse::<LevelFilter>) {
+        None => LevelFilter::Trace,
+        Some(Ok(level)) => level,
+        Some(Err(_)) => {
+            return writeln!(out, "usage: dmesg [error|warn|info|debug|trace]");
+        }
+    };
+
+    let lost = logger::lost();
+    if lost > 0 {
+        writeln!(out, "({} older messages lost)", lost)?;
+    }
+    let mut result = Ok(());
+    logger::for_each(level, |entry| {
+        result = result.and(writeln!(out, "{}", entry));
+    });
+    result
+}
+
+fn heap<'a>(out: &mut dyn Write, mut args: impl Iterator<Item = &'a str>) -> fmt::Result {
+    match (args.next(), args.next()) {
+        (None, _) => print_heap_stats(out),
+        (Some("track"), Some("on")) => {
+            stats::set_tracking(true);
+            writeln!(out, "allocation tracking enabled")
+        }
+        (Some("track"), Some("off")) => {
+            stats::set_tracking(false);
+            writeln!(out, "allocation tracking disabled")
+        }
+        (Some("leaks"), _) => print_leaks(out),
+        _ => writeln!(out, "usage: heap [track on|off | leaks]"),
+    }
+}
+
+fn print_heap_stats(out: &mut dyn Write) -> fmt::Result {
+    let counters = stats::counters();
+
+    writeln!(
+        out,
+        "heap: {} bytes mapped (limit {})",
+        allocator::heap_size(),
+        allocator::heap_limit()
+    )?;
+    writeln!(
+        out,
+        "allocs {} deallocs {} in use {} bytes peak {} bytes",
+        counters.allocs, counters.deallocs, counters.bytes_in_use, counters.peak_bytes
+    )?;
+    #[cfg(feature = "alloc-fixed-block")]
+    print_block_stats(out)?;
+    Ok(())
+}
+
+// サイズごとの内訳はFixedSizeBlockAllocatorでのみ表示できる
+#[cfg(feature = "alloc-fixed-block")]
+fn print_block_stats(out: &mut dyn Write) -> fmt::Result {
+    let blocks = allocator::block_stats();
+
+    writeln!(
+        out,
+        "fallback: {} / {} bytes used, {} bytes free in slabs",
+        blocks.fallback_used,
+        blocks.fallback_size,
+        blocks.free_block_bytes()
+    )?;
+    writeln!(
+        out,
+        "{:>6} {:>6} {:>6} {:>6}",
+        "class", "live", "free", "slabs"
+    )?;
+    for class in blocks.classes.iter() {
+        writeln!(
+            out,
+            "{:>6} {:>6} {:>6} {:>6}",
+            class.block_size, class.live, class.free, class.slabs
+        )?;
+    }
+    Ok(())
+}
+
+fn irqs(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "{:>6} {:>10}  handlers", "vector", "count")?;
+    for vector in irq::FIRST_ISA_VECTOR..=irq::LAST_DYNAMIC_VECTOR {
+        let count = irq::count(vector);
+        let mut handlers = 0;
+        let mut result = Ok(());
+        irq::for_each_handler(vector, |name| {
+            if handlers == 0 {
+                result = result.and(write!(out, "{:>6} {:>10} ", vector, count));
+            }
+            result = result.and(write!(out, " {}", name));
+            handlers += 1;
+        });
+        result?;
+        if handlers > 0 {
+            writeln!(out)?;
+        } else if count > 0 {
+            writeln!(out, "{:>6} {:>10}  (none)", vector, count)?;
+        }
+    }
+    writeln!(out, "spurious: {}", irq::spurious_count())
+}
+
+fn uptime(out: &mut dyn Write) -> fmt::Result {
+    let uptime = time::uptime();
+    writeln!(
+        out,
+        "up {}.{:03} s ({} ticks)",
+        uptime.as_secs(),
+        uptime.subsec_millis(),
+        time::ticks()
+    )
+}
+
+fn print_leaks(out: &mut dyn Write) -> fmt::Result {
+    if !stats::is_tracking() {
+        return writeln!(out, "tracking is off; run 'heap track on' first");
+    }
+
+    let mut count = 0;
+    let mut result = Ok(());
+    let dropped = stats::for_each_live(0, |record| {
+        result = result.and(print_leak(out, record));
+        count += 1;
+    });
+    result?;
+    writeln!(out, "{} live allocations ({} not recorded)", count, dropped)
+}
+
+fn print_leak(out: &mut dyn Write, record: &stats::AllocRecord) -> fmt::Result {
+    write!(
+        out,
+        "#{:<6} {:#x} {:>6} bytes  ",
+        record.seq, record.addr, record.size
+    )?;
+    match record.caller() {
+        Some(address) => backtrace::write_address(out, address)?,
+        None => write!(out, "?")?,
+    }
+    if let Some(site) = record.site {
+        write!(out, " ({})", site)?;
+    }
+    writeln!(out)
+}
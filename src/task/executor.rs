@@ -1,4 +1,5 @@
 use super::{Task, TaskId};
+use crate::watchdog;
 use alloc::task::Wake;
 use alloc::{collections::BTreeMap, sync::Arc};
 use core::task::{Context, Poll, Waker};
@@ -44,7 +45,11 @@ impl Executor {
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            // pollが戻ってこなければwatchdogが報告する
+            watchdog::poll_started(task_id.0);
+            let poll = task.poll(&mut context);
+            watchdog::poll_finished();
+            match poll {
                 Poll::Ready(()) => {
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
@@ -61,9 +66,23 @@ impl Executor {
         }
     }
 
+    /// 全てのタスクが完了するまで実行する。
+    pub fn run_until_complete(&mut self) {
+        loop {
+            self.run_ready_tasks();
+            if self.tasks.is_empty() {
+                break;
+            }
+            self.sleep_if_idle();
+        }
+    }
+
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
+        // 割り込みハンドラからの出力を休む前に書き出す
+        crate::console::flush_deferred();
+
         // 割り込みを無効
         interrupts::disable();
         if self.task_queue.is_empty() {
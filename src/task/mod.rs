@@ -5,7 +5,9 @@ use core::{future::Future, pin::Pin};
 
 pub mod executor;
 pub mod keyboard;
+pub mod serial;
 pub mod simple_executor;
+pub mod timer;
 
 pub struct Task {
     id: TaskId,
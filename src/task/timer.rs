@@ -0,0 +1,218 @@
+// タイマ割り込みで起こされるFuture
+// 待っているFutureのWakerを期限順に並べておき、タイマ割り込みのハンドラが期限の来たものを起こす
+
+use crate::time::{self, Instant};
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+struct TimerEntry {
+    waker: Waker,
+    // 割り込みハンドラが既に起こしたか
+    fired: bool,
+}
+
+// (期限のtick, タイマのID) の順に並ぶ
+// 割り込みハンドラはヒープを使えないので、エントリの削除はFuture側で行う
+static TIMERS: Mutex<BTreeMap<(u64, u64), TimerEntry>> = Mutex::new(BTreeMap::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// タイマ割り込みのハンドラから呼ばれ、期限の来たタイマのタスクを起こす。
+///
+/// エントリを削除するとWakerのdropでヒープを解放することがあるので、
+/// ここでは起こした印を付けるだけにする。
+pub(crate) fn wake_expired() {
+    // タスク側は割り込みを無効にしてロックを取るので、取れないのは割り込みの入れ子だけ
+    let mut timers = match TIMERS.try_lock() {
+        Some(timers) => timers,
+        None => return,
+    };
+    let now = time::ticks();
+    for (_, entry) in timers.range_mut(..=(now, u64::MAX)) {
+        if !entry.fired {
+            entry.fired = true;
+            entry.waker.wake_by_ref();
+        }
+    }
+}
+
+fn with_timers<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BTreeMap<(u64, u64), TimerEntry>) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut TIMERS.lock()))
+}
+
+/// `duration`が経過すると完了するFutureを返す。
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// `deadline`になると完了するFutureを返す。
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+pub struct Sleep {
+    deadline: Instant,
+    id: u64,
+    // TIMERSにエントリがあるか
+    registered: bool,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// 期限を変えて待ち直す。
+    pub fn reset(&mut self, deadline: Instant) {
+        self.unregister();
+        self.deadline = deadline;
+    }
+
+    fn key(&self) -> (u64, u64) {
+        (self.deadline.ticks(), self.id)
+    }
+
+    fn unregister(&mut self) {
+        if self.registered {
+            let key = self.key();
+            with_timers(|timers| timers.remove(&key));
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+
+        let key = self.key();
+        let waker = cx.waker();
+        with_timers(|timers| match timers.get_mut(&key) {
+            // 別のタスクから poll された場合に備えて Waker を入れ替える
+            Some(entry) if !entry.waker.will_wake(waker) => {
+                entry.waker = waker.clone();
+                entry.fired = false;
+            }
+            Some(_) => {}
+            None => {
+                timers.insert(
+                    key,
+                    TimerEntry {
+                        waker: waker.clone(),
+                        fired: false,
+                    },
+                );
+            }
+        });
+        self.registered = true;
+
+        // 登録する前に期限が過ぎていた場合、割り込みでは起こされないので自分で確かめる
+        if Instant::now() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// `period`ごとに時刻を返すStreamを返す。最初の時刻は`period`後になる。
+///
+/// 処理が遅れても、次の時刻は前の予定時刻から`period`後になる。
+pub fn interval(period: Duration) -> Interval {
+    assert!(
+        period > Duration::from_secs(0),
+        "interval period must be non-zero"
+    );
+    Interval {
+        period,
+        sleep: sleep(period),
+    }
+}
+
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    /// 次の時刻まで待つ。
+    pub async fn tick(&mut self) -> Instant {
+        futures_util::StreamExt::next(self).await.unwrap()
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let deadline = self.sleep.deadline();
+                let next = deadline + self.period;
+                self.sleep.reset(next);
+                Poll::Ready(Some(deadline))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// `timeout`で期限が過ぎたことを表すエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// `future`が`duration`以内に完了しなければ`Err(Elapsed)`を返すFutureを返す。
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // futureはTimeoutと一緒に固定されており、ここから外に動かさない
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
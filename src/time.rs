@@ -0,0 +1,138 @@
+// PIT (8253/8254) のチャンネル0でタイマ割り込みを一定の周期で発生させ、起動からの時間を数える
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+pub mod rtc;
+
+// PITの入力クロック (Hz)
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+// タイマ割り込みの目標の周波数 (Hz)
+pub const TIMER_HZ: u64 = 1000;
+
+// PITに設定する分周比
+// 割り切れないので、実際の周波数は TIMER_HZ より僅かにずれる
+const DIVISOR: u64 = PIT_FREQUENCY / TIMER_HZ;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+// 起動してからのタイマ割り込みの回数
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// PITのチャンネル0を`TIMER_HZ`で割り込みを発生させるように設定する。
+///
+/// 割り込みを有効にする前に呼び出すこと。
+pub fn init() {
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+
+    unsafe {
+        // 0x34 = チャンネル0、下位バイト→上位バイトの順にアクセス、モード2 (rate generator)、バイナリ
+        command.write(0x34);
+        channel_0.write((DIVISOR & 0xff) as u8);
+        channel_0.write((DIVISOR >> 8) as u8);
+    }
+}
+
+/// タイマ割り込みのハンドラから呼ばれ、カウンタを1進める。
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 起動してからのタイマ割り込みの回数を返す。
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 起動してからの経過時間を返す。
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// タイマ割り込みの回数を時間に変換する。
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * DIVISOR as u128 * NANOS_PER_SEC / PIT_FREQUENCY as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// タイマ割り込みの回数をミリ秒に変換する。
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks_to_duration(ticks).as_millis() as u64
+}
+
+/// 時間をタイマ割り込みの回数に変換する。端数は切り上げる。
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks_nanos = DIVISOR as u128 * NANOS_PER_SEC;
+    let nanos = duration.as_nanos() * PIT_FREQUENCY as u128;
+    ((nanos + ticks_nanos - 1) / ticks_nanos) as u64
+}
+
+/// ミリ秒をタイマ割り込みの回数に変換する。端数は切り上げる。
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    duration_to_ticks(Duration::from_millis(ms))
+}
+
+/// 起動してからの時刻。タイマ割り込みの回数で表す。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(ticks())
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        Instant(ticks)
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// `earlier`からの経過時間を返す。`earlier`の方が後なら0を返す。
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration_to_ticks(duration))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[test_case]
+fn conversions_round_trip() {
+    assert_eq!(ticks_to_ms(0), 0);
+    // 分周比が割り切れない分、1秒は TIMER_HZ より僅かに多い回数になる
+    assert!(ms_to_ticks(1000) >= TIMER_HZ && ms_to_ticks(1000) <= TIMER_HZ + 1);
+    assert_eq!(ticks_to_ms(ms_to_ticks(250)), 250);
+    assert!(ticks_to_duration(TIMER_HZ) >= Duration::from_millis(999));
+    assert!(ticks_to_duration(TIMER_HZ) <= Duration::from_millis(1001));
+}
+
+#[test_case]
+fn ticks_advance() {
+    let start = Instant::now();
+    while ticks() < start.ticks() + 10 {
+        x86_64::instructions::hlt();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(9));
+}
@@ -0,0 +1,264 @@
+// CMOSのRTC (Real-Time Clock) から日時を読む
+// 起動時に一度だけ読み、その後はタイマ割り込みの回数を足して現在時刻を求める
+
+use super::ticks_to_duration;
+use crate::acpi;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+// CMOSのレジスタ
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+// Status Aの更新中のbit
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// Status Bの24時間表記、バイナリ表記のbit
+const HOUR_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+// 12時間表記のとき、時の最上位bitが午後を表す
+const HOUR_PM: u8 = 1 << 7;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// initで読んだ時刻 (Unix時間) と、そのときのタイマ割り込みの回数
+static BOOT_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+static BOOT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// UTCの日時
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// 1970-01-01 00:00:00 UTCからの秒数に変換する。
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), self.month, self.day);
+        days as u64 * SECONDS_PER_DAY
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+
+    /// 1970-01-01 00:00:00 UTCからの秒数を日時に変換する。
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let (year, month, day) = civil_from_days((timestamp / SECONDS_PER_DAY) as i64);
+        let seconds = timestamp % SECONDS_PER_DAY;
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// RTCを読み、現在時刻の基準にする。
+///
+/// `time::init`と`acpi::init`の後に呼び出すこと。ACPIを読む前だと世紀のレジスタが分からない。
+pub fn init() {
+    let timestamp = read().to_unix_timestamp();
+    BOOT_TICKS.store(super::ticks(), Ordering::Relaxed);
+    BOOT_TIMESTAMP.store(timestamp, Ordering::Relaxed);
+}
+
+/// 現在のUnix時間 (秒) を返す。
+pub fn unix_timestamp() -> u64 {
+    let elapsed = super::ticks() - BOOT_TICKS.load(Ordering::Relaxed);
+    BOOT_TIMESTAMP.load(Ordering::Relaxed) + ticks_to_duration(elapsed).as_secs()
+}
+
+/// 現在の日時を返す。
+pub fn now() -> DateTime {
+    DateTime::from_unix_timestamp(unix_timestamp())
+}
+
+/// RTCから日時を直接読む。
+///
+/// RTCが時刻を更新している間は読まず、続けて二回読んで同じ値になるまで繰り返す。
+pub fn read() -> DateTime {
+    interrupts::without_interrupts(|| {
+        let mut current = read_registers();
+        loop {
+            let next = read_registers();
+            if next == current {
+                break;
+            }
+            current = next;
+        }
+        decode(current, read_cmos(REG_STATUS_B), century_register())
+    })
+}
+
+// (秒, 分, 時, 日, 月, 年, 世紀)
+type RawTime = [u8; 7];
+
+fn read_registers() -> RawTime {
+    while read_cmos(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    let century = century_register().map_or(0, read_cmos);
+    [
+        read_cmos(REG_SECONDS),
+        read_cmos(REG_MINUTES),
+        read_cmos(REG_HOURS),
+        read_cmos(REG_DAY),
+        read_cmos(REG_MONTH),
+        read_cmos(REG_YEAR),
+        century,
+    ]
+}
+
+// FADTに世紀のレジスタがあれば使う
+fn century_register() -> Option<u8> {
+    acpi::info()
+        .and_then(|info| info.fadt)
+        .map(|fadt| fadt.century_register)
+        .filter(|&register| register != 0)
+}
+
+fn decode(raw: RawTime, status_b: u8, century_register: Option<u8>) -> DateTime {
+    let [second, minute, hour, day, month, year, century] = raw;
+    let binary = status_b & BINARY != 0;
+    let value = |value: u8| if binary { value } else { from_bcd(value) };
+
+    let pm = hour & HOUR_PM != 0;
+    let mut hour = value(hour & !HOUR_PM);
+    if status_b & HOUR_24 == 0 {
+        // 12時間表記では0時が12時になる
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let year = u16::from(value(year));
+    let year = match century_register {
+        Some(_) => u16::from(value(century)) * 100 + year,
+        // 世紀が分からなければ2000年代とみなす
+        None => 2000 + year,
+    };
+
+    DateTime {
+        year,
+        month: value(month),
+        day: value(day),
+        hour,
+        minute: value(minute),
+        second: value(second),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn read_cmos(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(0x70);
+    let mut data: Port<u8> = Port::new(0x71);
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+// 1970-01-01からの日数 (Howard Hinnantのアルゴリズム)
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[test_case]
+fn unix_timestamp_round_trip() {
+    let epoch = DateTime {
+        year: 1970,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    assert_eq!(DateTime::from_unix_timestamp(0), epoch);
+    assert_eq!(epoch.to_unix_timestamp(), 0);
+
+    // うるう年の2月29日
+    let leap = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 12,
+        minute: 34,
+        second: 56,
+    };
+    assert_eq!(leap.to_unix_timestamp(), 1_709_210_096);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_210_096), leap);
+    assert_eq!(DateTime::from_unix_timestamp(951_782_400).month, 2);
+}
+
+#[test_case]
+fn decodes_bcd_and_12_hour() {
+    // 2024-12-31 11:59:58 PM (BCD、12時間表記)
+    let raw = [0x58, 0x59, HOUR_PM | 0x11, 0x31, 0x12, 0x24, 0x20];
+    let date = decode(raw, 0, Some(0x32));
+    assert_eq!(date.year, 2024);
+    assert_eq!((date.month, date.day), (12, 31));
+    assert_eq!((date.hour, date.minute, date.second), (23, 59, 58));
+
+    // 0時は12時と表される
+    let raw = [0, 0, 12, 1, 1, 25, 0];
+    assert_eq!(decode(raw, BINARY, None).hour, 0);
+    assert_eq!(decode(raw, BINARY, None).year, 2025);
+}
+
+#[test_case]
+fn rtc_reads_plausible_date() {
+    let date = read();
+    assert!(date.year >= 2020);
+    assert!((1..=12).contains(&date.month));
+    assert!((1..=31).contains(&date.day));
+}
@@ -94,6 +94,8 @@ impl Writer {
             match byte {
                 // 出力可能
                 0x20..=0x7e | b'\n' => self.write_byte(byte),
+                // バックスペースは一文字消す
+                0x08 => self.clear_word(),
 
                 // 出力不可能
                 _ => self.write_byte(0xfe),
@@ -128,19 +130,17 @@ impl Writer {
         }
     }
 
-    #[allow(dead_code)]
     pub fn clear_word(&mut self) {
         let blank = ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
 
+        // 行の先頭では何もしない (前の行は上に流れていて、戻ると書き込む行がずれる)
         if self.column_position == 0 {
-            self.column_position = BUFFER_WIDTH - 1;
-            self.row_position -= 1;
-        } else {
-            self.column_position -= 1;
+            return;
         }
+        self.column_position -= 1;
 
         let row = self.row_position;
         let col = self.column_position;
@@ -176,19 +176,6 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
-}
-
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => {
-        ($crate::print!("{}\n",format_args!($($arg)*)));
-    }
-}
-
 // testクレートは標準ライブラリに依存している
 // no_std環境下ではtestは使えない！
 // test_caseはさまざまな引数でのテストが可能
@@ -222,3 +209,20 @@ fn test_println_output() {
         }
     });
 }
+
+#[test_case]
+fn test_backspace_stops_at_line_start() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let row = writer.row_position;
+        write!(writer, "\nx\nab\u{8}\u{8}\u{8}\u{8}").expect("write failed");
+
+        assert_eq!(writer.row_position, row);
+        assert_eq!(writer.column_position, 0);
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 1][0].read();
+        assert_eq!(char::from(screen_char.ascii_character), ' ');
+        // 前の行は消えない
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][0].read();
+        assert_eq!(char::from(screen_char.ascii_character), 'x');
+    });
+}
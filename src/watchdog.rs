@@ -0,0 +1,205 @@
+// タスクのpollが戻ってこない、割り込みが止まらないといった異常をタイマ割り込みから見つける
+// 見つけたらログに報告するか、診断を付けてpanicする
+
+use crate::backtrace::{self, Backtrace};
+use crate::interrupts::irq;
+use crate::{console, println, time};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+
+/// 異常を見つけたときの動作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Action {
+    /// ログに報告して実行を続ける
+    Report,
+    /// 診断を付けてpanicする
+    Panic,
+}
+
+// 割り込みの回数を比べる間隔
+const RATE_WINDOW_MS: u64 = 1000;
+const VECTOR_COUNT: usize = (irq::LAST_DYNAMIC_VECTOR - irq::FIRST_ISA_VECTOR + 1) as usize;
+
+static ACTION: AtomicU8 = AtomicU8::new(Action::Report as u8);
+// pollがこれより長く戻らなければ報告する
+static HUNG_TASK_MS: AtomicU64 = AtomicU64::new(5_000);
+// 1秒あたりの割り込みがこれより多ければ報告する
+static STORM_THRESHOLD: AtomicU64 = AtomicU64::new(20_000);
+
+// pollを始めたときのタイマ割り込みの回数+1 (0ならpoll中でない)
+static POLL_START: AtomicU64 = AtomicU64::new(0);
+static POLL_TASK: AtomicU64 = AtomicU64::new(0);
+// 今のpollについて報告済みか
+static POLL_REPORTED: AtomicBool = AtomicBool::new(false);
+
+// 今の区間が始まったときのタイマ割り込みの回数と、そのときの各ベクタの割り込みの回数
+static WINDOW_START: AtomicU64 = AtomicU64::new(0);
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static WINDOW_COUNTS: [AtomicU64; VECTOR_COUNT] = [ZERO; VECTOR_COUNT];
+
+// 見つけた異常の数
+static HUNG_TASKS: AtomicU64 = AtomicU64::new(0);
+static STORMS: AtomicU64 = AtomicU64::new(0);
+
+/// 異常を見つけたときの動作を設定する。
+pub fn set_action(action: Action) {
+    ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+pub fn action() -> Action {
+    match ACTION.load(Ordering::Relaxed) {
+        0 => Action::Report,
+        _ => Action::Panic,
+    }
+}
+
+/// タスクのpollが戻らないとみなす時間を設定する。
+pub fn set_hung_task_timeout(timeout: Duration) {
+    HUNG_TASK_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// 割り込みの嵐とみなす、一つのベクタの1秒あたりの割り込みの回数を設定する。
+pub fn set_storm_threshold(per_second: u64) {
+    STORM_THRESHOLD.store(per_second, Ordering::Relaxed);
+}
+
+/// これまでに見つけた (戻らないpollの数, 割り込みの嵐の数) を返す。
+pub fn detected() -> (u64, u64) {
+    (
+        HUNG_TASKS.load(Ordering::Relaxed),
+        STORMS.load(Ordering::Relaxed),
+    )
+}
+
+/// Executorがタスクをpollする直前に呼ぶ。
+pub fn poll_started(task_id: u64) {
+    POLL_TASK.store(task_id, Ordering::Relaxed);
+    POLL_REPORTED.store(false, Ordering::Relaxed);
+    POLL_START.store(time::ticks() + 1, Ordering::Release);
+}
+
+/// Executorのpollが戻ったときに呼ぶ。
+pub fn poll_finished() {
+    POLL_START.store(0, Ordering::Release);
+}
+
+/// タイマ割り込みのハンドラから呼ばれる。
+pub(crate) fn tick() {
+    let now = time::ticks();
+    check_hung_task(now);
+    check_interrupt_rates(now);
+}
+
+fn check_hung_task(now: u64) {
+    let start = POLL_START.load(Ordering::Acquire);
+    if start == 0 {
+        return;
+    }
+    let elapsed = time::ticks_to_ms(now.saturating_sub(start - 1));
+    if elapsed < HUNG_TASK_MS.load(Ordering::Relaxed) || POLL_REPORTED.swap(true, Ordering::Relaxed)
+    {
+        return;
+    }
+    HUNG_TASKS.fetch_add(1, Ordering::Relaxed);
+
+    let task = POLL_TASK.load(Ordering::Relaxed);
+    match action() {
+        // panicのバックトレースが、割り込まれたタスクの中を指す
+        Action::Panic => panic!(
+            "watchdog: task {} has not returned from poll for {} ms",
+            task, elapsed
+        ),
+        Action::Report => {
+            log::error!(
+                "task {} has not returned from poll for {} ms",
+                task,
+                elapsed
+            );
+            // 割り込みハンドラの出力はCPUごとに決まった数しか溜められないので、1行ごとに書き出す
+            flush();
+            for &address in Backtrace::capture().frames() {
+                println!("  {}", Frame(address));
+                flush();
+            }
+        }
+    }
+}
+
+fn check_interrupt_rates(now: u64) {
+    let elapsed = time::ticks_to_ms(now - WINDOW_START.load(Ordering::Relaxed));
+    if elapsed < RATE_WINDOW_MS {
+        return;
+    }
+    WINDOW_START.store(now, Ordering::Relaxed);
+
+    let threshold = STORM_THRESHOLD.load(Ordering::Relaxed) * elapsed / 1000;
+    let mut found = false;
+    for (i, last) in WINDOW_COUNTS.iter().enumerate() {
+        let vector = irq::FIRST_ISA_VECTOR + i as u8;
+        let count = irq::count(vector);
+        let interrupts = count - last.swap(count, Ordering::Relaxed);
+        if interrupts <= threshold {
+            continue;
+        }
+        STORMS.fetch_add(1, Ordering::Relaxed);
+        found = true;
+
+        match action() {
+            Action::Panic => panic!(
+                "watchdog: interrupt storm on vector {} ({}): {} interrupts in {} ms",
+                vector,
+                Handlers(vector),
+                interrupts,
+                elapsed
+            ),
+            Action::Report => log::warn!(
+                "interrupt storm on vector {} ({}): {} interrupts in {} ms",
+                vector,
+                Handlers(vector),
+                interrupts,
+                elapsed
+            ),
+        }
+    }
+    if found {
+        flush();
+    }
+}
+
+// 割り込みハンドラの中の出力はタスクの側で書き出されるが、タスクが止まっていると出てこないので、ここで書き出す
+// コンソールのロックは割り込みを止めてから取るので、タイマ割り込みに割り込まれた側は持っていない
+fn flush() {
+    console::flush_deferred();
+}
+
+// バックトレースの1フレーム
+struct Frame(u64);
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        backtrace::write_address(f, self.0)
+    }
+}
+
+// ベクタに登録されているハンドラの名前
+struct Handlers(u8);
+
+impl fmt::Display for Handlers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut result = Ok(());
+        let mut first = true;
+        irq::for_each_handler(self.0, |name| {
+            if !first {
+                result = result.and(f.write_str(", "));
+            }
+            result = result.and(f.write_str(name));
+            first = false;
+        });
+        if first {
+            result = result.and(f.write_str("no handler"));
+        }
+        result
+    }
+}
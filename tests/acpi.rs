@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jura_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use jura_os::acpi::{self, AcpiError};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use jura_os::allocator;
+    use jura_os::memory::{self, GlobalFrameAllocator};
+
+    jura_os::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+    memory::with_mapper(|mapper| allocator::init_heap(mapper, &mut GlobalFrameAllocator))
+        .expect("heap intialization failed");
+    acpi::init().expect("ACPI initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jura_os::test_panic_handler(&info);
+}
+
+#[test_case]
+fn madt_lists_cpus_and_io_apic() {
+    let info = acpi::info().unwrap();
+    assert!(info.tables.iter().any(|table| &table.signature == b"APIC"));
+    assert!(info.cpu_count() >= 1);
+    assert_ne!(info.local_apic_address, 0);
+    // QEMUのIO-APICはGSI 0から始まる
+    assert_eq!(info.io_apic_for(0).unwrap().address, 0xfec0_0000);
+}
+
+#[test_case]
+fn pit_irq_is_overridden() {
+    // QEMUではPITのIRQ 0はGSI 2に繋がっている
+    let info = acpi::info().unwrap();
+    assert_eq!(info.isa_override(0).map(|entry| entry.gsi), Some(2));
+}
+
+#[test_case]
+fn fadt_has_power_registers() {
+    let fadt = acpi::info().unwrap().fadt.expect("no FADT");
+    assert_ne!(fadt.pm1a_control_block, 0);
+    assert_ne!(fadt.pm_timer_block, 0);
+}
+
+#[test_case]
+fn hpet_is_found() {
+    // QEMUのHPETは0xfed00000にあり、3つ以上のコンパレータを持つ
+    let info = acpi::info().unwrap();
+    assert!(info.tables.iter().any(|table| &table.signature == b"HPET"));
+    let hpet = info.hpet.expect("no HPET");
+    assert_eq!(hpet.base_address, 0xfed0_0000);
+    assert!(hpet.comparators >= 3);
+}
+
+#[test_case]
+fn init_twice_fails() {
+    assert_eq!(acpi::init(), Err(AcpiError::AlreadyInitialized));
+}
@@ -0,0 +1,213 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jura_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use jura_os::memory::address_space::{AddressSpace, AddressSpaceError};
+use jura_os::memory::{phys_to_virt, with_frame_allocator};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// カーネルが使っていないレベル4エントリに入るアドレス
+const USER_ADDR: u64 = 0x0000_7000_0000_0000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use jura_os::allocator;
+    use jura_os::memory::{self, GlobalFrameAllocator};
+
+    jura_os::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+    memory::with_mapper(|mapper| allocator::init_heap(mapper, &mut GlobalFrameAllocator))
+        .expect("heap intialization failed");
+    memory::vmalloc::init();
+    memory::address_space::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jura_os::test_panic_handler(&info);
+}
+
+#[test_case]
+fn user_page_visible_after_activate() {
+    let mut space = AddressSpace::new().expect("address space creation failed");
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let frame = space
+        .map_user(page, PageTableFlags::WRITABLE)
+        .expect("map_user failed");
+
+    // 物理メモリのマッピングを通して書き込み、切り替え後にユーザアドレスから読む
+    unsafe { *phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = 0xdead_beef };
+    unsafe { space.activate() };
+    let value = unsafe { *(USER_ADDR as *const u64) };
+    AddressSpace::activate_kernel();
+
+    assert_eq!(value, 0xdead_beef);
+}
+
+#[test_case]
+fn kernel_region_is_rejected() {
+    let mut space = AddressSpace::new().expect("address space creation failed");
+    let heap = Page::containing_address(VirtAddr::new(jura_os::allocator::HEAP_START as u64));
+    match space.map_user(heap, PageTableFlags::WRITABLE) {
+        Err(AddressSpaceError::KernelRegion) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test_case]
+fn reserved_kernel_slots_are_rejected() {
+    use jura_os::memory::{region, vmalloc};
+
+    let mut space = AddressSpace::new().expect("address space creation failed");
+    // まだ何もマップされていなくても、カーネル用に予約した範囲には置けない
+    for &addr in &[vmalloc::VMALLOC_END - 4096, region::REGION_START] {
+        let page = Page::containing_address(VirtAddr::new(addr));
+        match space.map_user(page, PageTableFlags::WRITABLE) {
+            Err(AddressSpaceError::KernelRegion) => {}
+            other => panic!("unexpected result for {:#x}: {:?}", addr, other),
+        }
+    }
+}
+
+#[test_case]
+fn kernel_mappings_after_creation_are_shared() {
+    use jura_os::memory::vmalloc;
+
+    // アドレス空間を作った後にカーネルがvmallocでマップしたページも見える
+    let space = AddressSpace::new().expect("address space creation failed");
+    let region = vmalloc::vmalloc(4096, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    let ptr: *mut u64 = region.as_mut_ptr();
+    unsafe { ptr.write_volatile(0x1234_5678) };
+
+    unsafe { space.activate() };
+    let value = unsafe { ptr.read_volatile() };
+    AddressSpace::activate_kernel();
+    vmalloc::vfree(region).expect("vfree failed");
+
+    assert_eq!(value, 0x1234_5678);
+}
+
+#[test_case]
+fn drop_frees_page_table_frames() {
+    let free = with_frame_allocator(|allocator| allocator.free_frames());
+    {
+        let mut space = AddressSpace::new().expect("address space creation failed");
+        for i in 0..4 {
+            let page = Page::containing_address(VirtAddr::new(USER_ADDR + i * 4096));
+            space
+                .map_user(page, PageTableFlags::WRITABLE)
+                .expect("map_user failed");
+        }
+        space
+            .unmap_user(Page::containing_address(VirtAddr::new(USER_ADDR)))
+            .expect("unmap_user failed");
+    }
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.free_frames()),
+        free
+    );
+}
+
+#[test_case]
+fn cow_write_copies_the_frame() {
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let mut parent = AddressSpace::new().expect("address space creation failed");
+    let mut child = AddressSpace::new().expect("address space creation failed");
+    let frame = parent
+        .map_user(page, PageTableFlags::WRITABLE)
+        .expect("map_user failed");
+    unsafe { *phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = 1 };
+    parent
+        .share_cow(page, &mut child, page)
+        .expect("share_cow failed");
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.ref_count(frame)),
+        2
+    );
+
+    // 書き込むとページフォルトで複製され、相手には見えない
+    unsafe { parent.activate() };
+    unsafe { (USER_ADDR as *mut u64).write_volatile(2) };
+    let parent_value = unsafe { (USER_ADDR as *const u64).read_volatile() };
+    unsafe { child.activate() };
+    let child_value = unsafe { (USER_ADDR as *const u64).read_volatile() };
+    AddressSpace::activate_kernel();
+
+    assert_eq!(parent_value, 2);
+    assert_eq!(child_value, 1);
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.ref_count(frame)),
+        1
+    );
+}
+
+#[test_case]
+fn cow_last_owner_writes_in_place() {
+    let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+    let mut parent = AddressSpace::new().expect("address space creation failed");
+    let frame = parent
+        .map_user(page, PageTableFlags::WRITABLE)
+        .expect("map_user failed");
+    {
+        let mut child = AddressSpace::new().expect("address space creation failed");
+        parent
+            .share_cow(page, &mut child, page)
+            .expect("share_cow failed");
+    }
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.ref_count(frame)),
+        1
+    );
+
+    // 共有相手がいなくなったので、複製せずに書き込めるようになる
+    let free = with_frame_allocator(|allocator| allocator.free_frames());
+    unsafe { parent.activate() };
+    unsafe { (USER_ADDR as *mut u64).write_volatile(3) };
+    AddressSpace::activate_kernel();
+
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.free_frames()),
+        free
+    );
+    assert_eq!(
+        unsafe { *phys_to_virt(frame.start_address()).as_ptr::<u64>() },
+        3
+    );
+}
+
+#[test_case]
+fn cow_frames_are_freed_with_both_spaces() {
+    let free = with_frame_allocator(|allocator| allocator.free_frames());
+    {
+        let page = Page::containing_address(VirtAddr::new(USER_ADDR));
+        let mut parent = AddressSpace::new().expect("address space creation failed");
+        let mut child = AddressSpace::new().expect("address space creation failed");
+        parent
+            .map_user(page, PageTableFlags::WRITABLE)
+            .expect("map_user failed");
+        parent
+            .share_cow(page, &mut child, page)
+            .expect("share_cow failed");
+
+        unsafe { child.activate() };
+        unsafe { (USER_ADDR as *mut u64).write_volatile(4) };
+        AddressSpace::activate_kernel();
+    }
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.free_frames()),
+        free
+    );
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jura_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use jura_os::apic;
+use jura_os::interrupts::InterruptIndex;
+use jura_os::time;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use jura_os::memory;
+
+    jura_os::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+    memory::vmalloc::init();
+    apic::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jura_os::test_panic_handler(&info);
+}
+
+#[test_case]
+fn apic_replaces_pic() {
+    assert!(apic::is_supported());
+    assert!(apic::is_enabled());
+    assert!(apic::IO_APIC.lock().is_some());
+}
+
+#[test_case]
+fn apic_timer_advances_ticks() {
+    // PITの割り込みは止めているので、進むのはLocal APICのタイマによる
+    let start = time::ticks();
+    while time::ticks() < start + 10 {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn interrupt_index_keeps_isa_routing() {
+    assert_eq!(InterruptIndex::Timer.isa_irq(), 0);
+    assert_eq!(InterruptIndex::Keyboard.isa_irq(), 1);
+    assert_eq!(
+        InterruptIndex::Keyboard.as_u8(),
+        jura_os::interrupts::PIC_1_OFFSET + 1
+    );
+}
@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jura_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::ptr;
+use jura_os::console::{self, ConsoleError, RingBuffer, HISTORY};
+use jura_os::interrupts::irq::{self, IrqReturn};
+use jura_os::println;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    jura_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jura_os::test_panic_handler(&info);
+}
+
+// 直近の出力が`expected`で終わっているか
+fn ends_with(buffer: &RingBuffer<64>, expected: &str) -> bool {
+    let mut bytes = [0; 64];
+    let len = buffer.copy_to(&mut bytes);
+    bytes[..len].ends_with(expected.as_bytes())
+}
+
+#[test_case]
+fn println_reaches_default_sinks() {
+    let mut names = [""; 8];
+    let mut count = 0;
+    console::for_each_sink(|sink| {
+        names[count] = sink.name();
+        count += 1;
+    });
+    assert_eq!(&names[..count], &["vga", "serial", "history"]);
+
+    println!("console history test");
+    let mut bytes = [0; 21];
+    let len = HISTORY.copy_to(&mut bytes);
+    assert_eq!(&bytes[..len], b"console history test\n");
+}
+
+#[test_case]
+fn added_sink_receives_output_until_removed() {
+    static CAPTURE: RingBuffer<64> = RingBuffer::new("capture");
+
+    console::add_sink(&CAPTURE).unwrap();
+    assert_eq!(console::add_sink(&CAPTURE), Err(ConsoleError::AlreadyAdded));
+    println!("captured {}", 42);
+    assert!(ends_with(&CAPTURE, "captured 42\n"));
+
+    assert!(console::remove_sink("capture"));
+    assert!(!console::remove_sink("capture"));
+    println!("not captured");
+    assert!(ends_with(&CAPTURE, "captured 42\n"));
+}
+
+#[test_case]
+fn ring_buffer_keeps_newest_bytes() {
+    static RING: RingBuffer<64> = RingBuffer::new("ring");
+
+    for i in 0..20 {
+        write!(console::SinkWriter(&RING), "{:04}", i).unwrap();
+    }
+    assert_eq!(RING.len(), 64);
+    let mut bytes = [0; 64];
+    RING.copy_to(&mut bytes);
+    assert!(bytes.starts_with(b"0004"));
+    assert!(bytes.ends_with(b"0019"));
+
+    RING.clear();
+    assert!(RING.is_empty());
+}
+
+#[test_case]
+fn interrupt_handler_output_is_deferred() {
+    const VECTOR: u8 = irq::FIRST_DYNAMIC_VECTOR;
+    static CAPTURE: RingBuffer<64> = RingBuffer::new("deferred");
+
+    fn print_from_handler(_context: *mut ()) -> IrqReturn {
+        println!("from handler");
+        IrqReturn::Handled
+    }
+
+    assert_eq!(irq::allocate_vector(), Ok(VECTOR));
+    let id = irq::register(VECTOR, "console test", print_from_handler, ptr::null_mut()).unwrap();
+    console::add_sink(&CAPTURE).unwrap();
+
+    unsafe { asm!("int {}", const VECTOR) };
+    // ハンドラの中ではロックを取らずに溜めるだけ
+    assert!(CAPTURE.is_empty());
+    console::flush_deferred();
+    assert!(ends_with(&CAPTURE, "from handler\n"));
+
+    console::remove_sink("deferred");
+    irq::free_handler(id).unwrap();
+    irq::free_vector(VECTOR).unwrap();
+}
+
+#[test_case]
+fn breakpoint_report_fits_in_one_message() {
+    static CAPTURE: RingBuffer<256> = RingBuffer::new("breakpoint");
+
+    console::add_sink(&CAPTURE).unwrap();
+    x86_64::instructions::interrupts::int3();
+    console::flush_deferred();
+    console::remove_sink("breakpoint");
+
+    let mut bytes = [0; 256];
+    let len = CAPTURE.copy_to(&mut bytes);
+    assert!(bytes[..len].starts_with(b"EXCEPTION: BREAKPOINT RIP "));
+    // 切り詰められていれば改行で終わらない
+    assert!(bytes[..len].ends_with(b"\n"));
+}
@@ -0,0 +1,255 @@
+// 例外の種類ごとに、ハンドラが報告を出してpanicすることの確認
+// panicするとテストを続けられないので、panicハンドラから次の例外を起こす
+// 報告はコンソールの履歴から読み返し、エラーコードの解釈やレジスタの値も確かめる
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use jura_os::console::{self, HISTORY_SIZE};
+use jura_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::structures::gdt::{Descriptor, DescriptorFlags, GlobalDescriptorTable};
+
+struct Case {
+    name: &'static str,
+    trigger: fn(),
+    // panicのメッセージ
+    message: &'static str,
+    // 報告に含まれるはずの行
+    report: &'static [&'static str],
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "divide_error",
+        trigger: divide_error,
+        message: "EXCEPTION: DIVIDE ERROR",
+        report: &["(vector 0)", "error code: none"],
+    },
+    Case {
+        name: "overflow",
+        trigger: overflow,
+        message: "EXCEPTION: OVERFLOW",
+        report: &["(vector 4)", "error code: none"],
+    },
+    Case {
+        name: "invalid_opcode",
+        trigger: invalid_opcode,
+        message: "EXCEPTION: INVALID OPCODE",
+        // 例外の時点のレジスタの値が報告される
+        report: &[
+            "error code: none",
+            "RBX 0x0123456789abcdef",
+            "R12 0xfedcba9876543210",
+        ],
+    },
+    Case {
+        name: "general_protection_fault",
+        trigger: general_protection_fault,
+        message: "EXCEPTION: GENERAL PROTECTION FAULT",
+        report: &["error code: 0x1230 (GDT index 582)"],
+    },
+    Case {
+        name: "segment_not_present",
+        trigger: segment_not_present,
+        message: "EXCEPTION: SEGMENT NOT PRESENT",
+        report: &["(vector 11)", "error code: 0x10 (GDT index 2)"],
+    },
+    Case {
+        name: "stack_segment_fault",
+        trigger: stack_segment_fault,
+        message: "EXCEPTION: STACK SEGMENT FAULT",
+        report: &["(vector 12)", "error code: 0x18 (GDT index 3)"],
+    },
+    Case {
+        name: "page_fault",
+        trigger: page_fault,
+        message: "EXCEPTION: PAGE FAULT",
+        report: &["error code: 0x2 CAUSED_BY_WRITE", "CR2 0xdeadbeef000"],
+    },
+    // ダブルフォルトのハンドラはISTのスタックで動くので最後にする
+    Case {
+        name: "double_fault",
+        trigger: double_fault,
+        message: "EXCEPTION: DOUBLE FAULT",
+        report: &["(vector 8)", "error code: 0x0"],
+    },
+];
+
+lazy_static! {
+    // 存在しないセグメントを読み込むためのGDT
+    // コードセグメントはカーネルのGDTと同じ位置に置き、CSはそのまま使えるようにする
+    static ref TEST_GDT: GlobalDescriptorTable = {
+        // PRESENTのbitがない、書き込み可能なデータセグメント
+        let not_present = || {
+            let flags = DescriptorFlags::USER_SEGMENT | DescriptorFlags::WRITABLE;
+            Descriptor::UserSegment(flags.bits())
+        };
+        let mut gdt = GlobalDescriptorTable::new();
+        gdt.add_entry(Descriptor::kernel_code_segment());
+        // 0x10: DSに読み込む
+        gdt.add_entry(not_present());
+        // 0x18: SSに読み込む
+        gdt.add_entry(not_present());
+        gdt
+    };
+}
+
+// panicハンドラで読み返した報告
+static REPORT: Mutex<[u8; HISTORY_SIZE]> = Mutex::new([0; HISTORY_SIZE]);
+
+// 実行中のテストの番号
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    jura_os::init();
+
+    // トラップは報告だけして実行を続ける
+    serial_print!("exceptions::breakpoint...\t");
+    x86_64::instructions::interrupts::int3();
+    serial_println!("[ok]");
+
+    run(0)
+}
+
+fn run(index: usize) -> ! {
+    match CASES.get(index) {
+        Some(case) => {
+            CURRENT.store(index, Ordering::SeqCst);
+            serial_print!("exceptions::{}...\t", case.name);
+            // 報告だけが残るように履歴を消しておく
+            console::HISTORY.clear();
+            (case.trigger)();
+            serial_println!("[no exception]");
+            exit_qemu(QemuExitCode::Failed);
+            loop {}
+        }
+        None => {
+            exit_qemu(QemuExitCode::Success);
+            loop {}
+        }
+    }
+}
+
+fn divide_error() {
+    unsafe {
+        asm!("div ecx", in("ecx") 0u32, inout("eax") 1u32 => _, inout("edx") 0u32 => _);
+    }
+}
+
+fn overflow() {
+    // 64bitモードではintoが使えないので、ベクタ4を直接呼ぶ
+    unsafe { asm!("int 4") };
+}
+
+fn invalid_opcode() {
+    // rbxはオペランドに指定できないので命令の中で入れる
+    // 例外から戻らないので、書き換えたまま戻さなくてよい
+    unsafe {
+        asm!(
+            "mov rbx, {0}",
+            "ud2",
+            in(reg) 0x0123_4567_89ab_cdefu64,
+            in("r12") 0xfedc_ba98_7654_3210u64,
+            options(noreturn)
+        )
+    };
+}
+
+fn general_protection_fault() {
+    // GDTの範囲外のセレクタを読み込む
+    unsafe { asm!("mov ds, {0:x}", in(reg) 0x1230u16) };
+}
+
+fn segment_not_present() {
+    TEST_GDT.load();
+    unsafe { asm!("mov ds, {0:x}", in(reg) 0x10u16) };
+}
+
+fn stack_segment_fault() {
+    TEST_GDT.load();
+    unsafe { asm!("mov ss, {0:x}", in(reg) 0x18u16) };
+}
+
+fn page_fault() {
+    unsafe { core::ptr::write_volatile(0xdead_beef_000 as *mut u8, 0) };
+}
+
+fn double_fault() {
+    // マップされていないスタックに積むとページフォルトになり、
+    // そのページフォルトのフレームも積めずにダブルフォルトになる
+    unsafe {
+        asm!(
+            "mov rsp, {0}",
+            "push rax",
+            in(reg) 0xdead_0000_0000u64,
+            options(noreturn)
+        )
+    };
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let case = &CASES[CURRENT.load(Ordering::SeqCst)];
+    let mut message = Buffer::new();
+    let _ = write!(message, "{}", info);
+
+    let missing = missing_in_report(case.report);
+
+    match (message.as_str().contains(case.message), missing) {
+        (true, None) => {
+            serial_println!("[ok]");
+            run(CURRENT.load(Ordering::SeqCst) + 1)
+        }
+        (_, missing) => {
+            serial_println!("[failed]\n");
+            serial_println!("Error: {}\n", info);
+            if let Some(line) = missing {
+                serial_println!("missing in the report: {}\n", line);
+            }
+            exit_qemu(QemuExitCode::Failed);
+            loop {}
+        }
+    }
+}
+
+// 報告に含まれていない行を返す
+fn missing_in_report(lines: &'static [&'static str]) -> Option<&'static str> {
+    let mut report = REPORT.lock();
+    let len = console::HISTORY.copy_to(&mut *report);
+    let report = core::str::from_utf8(&report[..len]).unwrap_or("");
+    lines.iter().copied().find(|line| !report.contains(line))
+}
+
+// panicのメッセージを比べるための、ヒープを使わない文字列
+struct Buffer {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl Buffer {
+    fn new() -> Self {
+        Buffer {
+            bytes: [0; 256],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
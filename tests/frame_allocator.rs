@@ -0,0 +1,113 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jura_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use jura_os::memory::with_frame_allocator;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use jura_os::memory;
+    use x86_64::VirtAddr;
+
+    jura_os::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset) };
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jura_os::test_panic_handler(&info);
+}
+
+#[test_case]
+fn allocate_and_free() {
+    with_frame_allocator(|allocator| {
+        let free = allocator.free_frames();
+        let frame: PhysFrame = allocator.allocate_frame().expect("allocation failed");
+        assert_eq!(allocator.free_frames(), free - 1);
+
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.free_frames(), free);
+    });
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    with_frame_allocator(|allocator| {
+        let frame: PhysFrame = allocator.allocate_frame().expect("allocation failed");
+        unsafe { allocator.deallocate_frame(frame) };
+
+        // 解放したフレームが再び割り当てられる
+        let again: PhysFrame = allocator.allocate_frame().expect("allocation failed");
+        assert_eq!(frame, again);
+        unsafe { allocator.deallocate_frame(again) };
+    });
+}
+
+#[test_case]
+fn many_frames_are_distinct() {
+    with_frame_allocator(|allocator| {
+        let mut frames = [None::<PhysFrame>; 64];
+        for slot in frames.iter_mut() {
+            *slot = allocator.allocate_frame();
+        }
+        for (i, a) in frames.iter().enumerate() {
+            for b in &frames[i + 1..] {
+                assert_ne!(a.unwrap(), b.unwrap());
+            }
+        }
+        for frame in frames.iter() {
+            unsafe { allocator.deallocate_frame(frame.unwrap()) };
+        }
+    });
+}
+
+#[test_case]
+fn contiguous_allocation() {
+    with_frame_allocator(|allocator| {
+        let free = allocator.free_frames();
+        let range = allocator
+            .allocate_contiguous(16)
+            .expect("allocation failed");
+
+        let mut expected = range.start.start_address().as_u64();
+        for frame in range {
+            assert_eq!(frame.start_address().as_u64(), expected);
+            expected += 4096;
+        }
+        assert_eq!(allocator.free_frames(), free - 16);
+        assert_eq!(
+            allocator.used_frames() + allocator.free_frames(),
+            allocator.usable_frames()
+        );
+
+        unsafe { allocator.deallocate_contiguous(range) };
+        assert_eq!(allocator.free_frames(), free);
+    });
+}
+
+#[test_case]
+fn shared_frame_is_freed_by_last_reference() {
+    with_frame_allocator(|allocator| {
+        let frame: PhysFrame = allocator.allocate_frame().expect("allocation failed");
+        assert_eq!(allocator.ref_count(frame), 1);
+        allocator.add_ref(frame);
+        assert_eq!(allocator.ref_count(frame), 2);
+
+        let free = allocator.free_frames();
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.free_frames(), free);
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.free_frames(), free + 1);
+        assert_eq!(allocator.ref_count(frame), 0);
+    });
+}
@@ -14,14 +14,17 @@ entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use jura_os::allocator;
-    use jura_os::memory::{self, BootInfoFrameAllocator};
+    use jura_os::memory::{self, GlobalFrameAllocator};
     use x86_64::VirtAddr;
 
     jura_os::init();
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(physical_memory_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap intialization failed");
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+    memory::with_mapper(|mapper| allocator::init_heap(mapper, &mut GlobalFrameAllocator))
+        .expect("heap intialization failed");
 
     test_main();
     loop {}
@@ -75,3 +78,213 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1);
 }
+
+// 大きな割り当てを連結リストアロケータ自身が扱う場合だけ、空き領域の結合を確かめられる
+#[cfg(feature = "alloc-linked-list")]
+#[test_case]
+fn fragmentation_stress() {
+    use alloc::alloc::{alloc, dealloc, Layout};
+    use fixed_heap::{fill, release, with_fixed_heap};
+
+    let chunk = Layout::from_size_align(4000, 8).unwrap();
+    with_fixed_heap(|| unsafe {
+        let (head, count) = fill(chunk);
+        assert!(count >= 2, "heap too small for the test");
+
+        // 一つおきに解放して穴だらけにしてから、残りを解放する
+        let mut block = head;
+        while !block.is_null() {
+            let next = *block as *mut usize;
+            if next.is_null() {
+                break;
+            }
+            let after = *next as *mut usize;
+            dealloc(next as *mut u8, chunk);
+            *block = after as usize;
+            block = after;
+        }
+        release(head, chunk);
+
+        // 隣り合う空き領域が結合されていれば大きな割り当てができる
+        let large = Layout::from_size_align(count / 2 * 4000, 8).unwrap();
+        let ptr = alloc(large);
+        assert!(!ptr.is_null());
+        dealloc(ptr, large);
+    });
+}
+
+#[test_case]
+fn heap_grows_on_demand() {
+    use alloc::vec::Vec;
+    use jura_os::allocator::{heap_size, HEAP_SIZE};
+
+    // 初期サイズより大きな割り当てをするとヒープが拡張される
+    let vec: Vec<u8> = alloc::vec![1; 2 * HEAP_SIZE];
+    assert!(heap_size() > HEAP_SIZE);
+    assert!(vec.iter().all(|&b| b == 1));
+}
+
+#[test_case]
+fn several_megabytes() {
+    use alloc::vec::Vec;
+
+    let n: u64 = 512 * 1024;
+    let vec: Vec<u64> = (0..n).collect();
+    assert_eq!(vec.len() * 8, 4 * 1024 * 1024);
+    assert_eq!(vec.iter().sum::<u64>(), n * (n - 1) / 2);
+}
+
+#[test_case]
+fn many_large_allocations() {
+    use alloc::vec::Vec;
+
+    // 1MiBのバッファを複数同時に保持する
+    let mut buffers = Vec::new();
+    for i in 0..6 {
+        buffers.push(alloc::vec![i as u8; 1024 * 1024]);
+    }
+    for (i, buffer) in buffers.iter().enumerate() {
+        assert!(buffer.iter().all(|&b| b == i as u8));
+    }
+}
+
+// サイズごとの内訳はFixedSizeBlockAllocatorでのみ取れる
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn stats_count_allocations() {
+    use alloc::boxed::Box;
+    use jura_os::allocator::{block_stats, stats};
+
+    let before = stats::counters();
+    let live_before = block_stats().classes[0].live;
+    let x = Box::new(1u8);
+    let during = stats::counters();
+    assert_eq!(during.allocs, before.allocs + 1);
+    assert_eq!(block_stats().classes[0].live, live_before + 1);
+    assert!(during.peak_bytes >= during.bytes_in_use);
+
+    drop(x);
+    let after = stats::counters();
+    assert_eq!(after.deallocs, before.deallocs + 1);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+}
+
+#[test_case]
+fn tracking_records_live_allocations() {
+    use alloc::boxed::Box;
+    use jura_os::allocator::stats;
+    use jura_os::backtrace;
+
+    stats::set_tracking(true);
+    let mark = stats::mark();
+    let x = {
+        let _site = jura_os::alloc_site!();
+        Box::new([0u64; 4])
+    };
+    let addr = &*x as *const _ as usize;
+
+    let mut found = false;
+    stats::for_each_live(mark, |record| {
+        if record.addr == addr {
+            assert_eq!(record.size, 32);
+            assert!(record.site.unwrap().contains("heap_allocation.rs"));
+            // alloc_site!がなくても、割り当てた関数が分かる
+            if backtrace::has_symbols() {
+                let (name, _) = record.caller().and_then(backtrace::symbolize).unwrap();
+                assert!(name.contains("tracking_records_live_allocations"));
+            }
+            found = true;
+        }
+    });
+    assert!(found);
+
+    drop(x);
+    let mut count = 0;
+    stats::for_each_live(mark, |_| count += 1);
+    assert_eq!(count, 0);
+    stats::set_tracking(false);
+}
+
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn memory_reusable_across_size_classes() {
+    use alloc::alloc::Layout;
+    use fixed_heap::{fill, release, with_fixed_heap};
+    use jura_os::allocator::{block_stats, heap_size};
+
+    fn slabs(block_size: usize) -> usize {
+        let stats = block_stats();
+        stats
+            .classes
+            .iter()
+            .find(|class| class.block_size == block_size)
+            .unwrap()
+            .slabs
+    }
+
+    let small = Layout::from_size_align(8, 8).unwrap();
+    let large = Layout::from_size_align(2048, 2048).unwrap();
+    with_fixed_heap(|| unsafe {
+        let size = heap_size();
+        let small_slabs = slabs(8);
+        let large_slabs = slabs(2048);
+
+        let (head, _) = fill(small);
+        // 代替のヒープに残っている空きはほとんどない
+        let stats = block_stats();
+        let free_before = stats.fallback_size - stats.fallback_used;
+        release(head, small);
+        // 空になったスラブは代替のヒープに返される (最後の1つだけは残る)
+        assert!(slabs(8) <= small_slabs.max(1));
+
+        let (head, _) = fill(large);
+        let new_slabs = slabs(2048) - large_slabs;
+        release(head, large);
+
+        // ヒープは拡張されていないので、残っていた空きを越える分は8バイトのスラブだったメモリから切り出されている
+        // (2048バイトのスラブは64KiB)
+        assert_eq!(heap_size(), size);
+        assert!(new_slabs * 64 * 1024 > free_before);
+    });
+}
+
+// ヒープの拡張を止めて、今あるメモリだけで試すための道具
+#[cfg(any(feature = "alloc-linked-list", feature = "alloc-fixed-block"))]
+mod fixed_heap {
+    use alloc::alloc::{alloc, dealloc, Layout};
+    use core::ptr;
+    use jura_os::allocator::{heap_limit, heap_size, set_heap_limit};
+
+    pub fn with_fixed_heap(f: impl FnOnce()) {
+        let limit = heap_limit();
+        set_heap_limit(heap_size());
+        f();
+        set_heap_limit(limit);
+    }
+
+    /// 割り当てられなくなるまで確保し、(最後に確保したブロック, 確保した数) を返す。
+    ///
+    /// Vecに記録するとそれ自体がヒープを使うので、ブロックの中に前のブロックのアドレスを書いてつなぐ。
+    pub unsafe fn fill(layout: Layout) -> (*mut usize, usize) {
+        let mut head: *mut usize = ptr::null_mut();
+        let mut count = 0;
+        loop {
+            let block = alloc(layout) as *mut usize;
+            if block.is_null() {
+                return (head, count);
+            }
+            *block = head as usize;
+            head = block;
+            count += 1;
+        }
+    }
+
+    /// `fill`でつないだブロックを全て解放する。
+    pub unsafe fn release(mut head: *mut usize, layout: Layout) {
+        while !head.is_null() {
+            let next = *head as *mut usize;
+            dealloc(head as *mut u8, layout);
+            head = next;
+        }
+    }
+}
@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jura_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use jura_os::memory::region::{self, MapRegionError};
+use jura_os::memory::{self, GlobalFrameAllocator};
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+// カーネルの物理領域用の範囲にある、2MiBでアラインされたアドレス
+const REGION_ADDR: u64 = region::REGION_START;
+const ANON_ADDR: u64 = region::REGION_START + 0x4000_0000;
+const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    jura_os::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jura_os::test_panic_handler(&info);
+}
+
+#[test_case]
+fn translate_physical_memory_mapping() {
+    // bootloaderは物理メモリを2MiBのページでマップしている
+    let offset = memory::physical_memory_offset();
+    let phys = unsafe { memory::translate_addr(offset + 0x20_1234u64, offset) };
+    assert_eq!(phys, Some(PhysAddr::new(0x20_1234)));
+}
+
+#[test_case]
+fn physical_region_is_mapped_with_huge_pages() {
+    let virt = VirtAddr::new(REGION_ADDR);
+    // 2MiBのページ1枚と4KiBのページ2枚になる
+    let size = HUGE_PAGE_SIZE + 2 * 4096;
+    let flags = PageTableFlags::WRITABLE;
+    memory::with_mapper(|mapper| unsafe {
+        region::map_physical_region(
+            virt,
+            PhysAddr::new(0),
+            size,
+            flags,
+            mapper,
+            &mut GlobalFrameAllocator,
+        )
+    })
+    .expect("map_physical_region failed");
+
+    memory::with_mapper(|mapper| {
+        for &offset in &[0, 0x1234, HUGE_PAGE_SIZE + 0x1234] {
+            assert_eq!(
+                mapper.translate_addr(virt + offset),
+                Some(PhysAddr::new(offset))
+            );
+        }
+    });
+
+    // 物理メモリのマッピングと同じ内容が見える
+    let direct = memory::phys_to_virt(PhysAddr::new(0x1000));
+    let value = unsafe { *(virt + 0x1000u64).as_ptr::<u64>() };
+    assert_eq!(value, unsafe { *direct.as_ptr::<u64>() });
+}
+
+#[test_case]
+fn unaligned_region_is_rejected() {
+    let result = memory::with_mapper(|mapper| unsafe {
+        region::map_physical_region(
+            VirtAddr::new(REGION_ADDR + 0x123),
+            PhysAddr::new(0),
+            4096,
+            PageTableFlags::WRITABLE,
+            mapper,
+            &mut GlobalFrameAllocator,
+        )
+    });
+    assert_eq!(result, Err(MapRegionError::Unaligned));
+}
+
+#[test_case]
+fn anonymous_region_is_zeroed_and_writable() {
+    let virt = VirtAddr::new(ANON_ADDR);
+    let size = 2 * HUGE_PAGE_SIZE;
+    memory::with_mapper(|mapper| {
+        region::map_anonymous(
+            virt,
+            size,
+            PageTableFlags::WRITABLE,
+            mapper,
+            &mut GlobalFrameAllocator,
+        )
+    })
+    .expect("map_anonymous failed");
+
+    let bytes = unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u8>(), size as usize) };
+    assert!(bytes.iter().all(|&b| b == 0));
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    assert!(bytes.iter().enumerate().all(|(i, &b)| b == i as u8));
+
+    // 2MiBのページ内のオフセットも正しく変換される
+    memory::with_mapper(|mapper| {
+        let start = mapper.translate_addr(virt).unwrap();
+        assert_eq!(
+            mapper.translate_addr(virt + 0x1234u64),
+            Some(start + 0x1234u64)
+        );
+    });
+}
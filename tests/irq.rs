@@ -0,0 +1,184 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jura_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use jura_os::interrupts::irq::{self, HandlerId, IrqError, IrqReturn};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use jura_os::allocator;
+    use jura_os::memory::{self, GlobalFrameAllocator};
+
+    jura_os::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+    memory::with_mapper(|mapper| allocator::init_heap(mapper, &mut GlobalFrameAllocator))
+        .expect("heap intialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jura_os::test_panic_handler(&info);
+}
+
+// テストで使うベクタ。最初に割り当てられるベクタになる
+const VECTOR: u8 = irq::FIRST_DYNAMIC_VECTOR;
+
+fn raise() {
+    unsafe { asm!("int {}", const VECTOR) };
+}
+
+fn count_calls(context: *mut ()) -> IrqReturn {
+    let calls = unsafe { &*(context as *const AtomicUsize) };
+    calls.fetch_add(1, Ordering::SeqCst);
+    IrqReturn::Handled
+}
+
+fn not_mine(_context: *mut ()) -> IrqReturn {
+    IrqReturn::NotMine
+}
+
+#[test_case]
+fn timer_and_keyboard_are_registered() {
+    assert!(irq::is_requested(0));
+    assert!(irq::is_requested(1));
+    let mut names = 0;
+    irq::for_each_handler(irq::FIRST_ISA_VECTOR, |name| {
+        assert_eq!(name, "timer");
+        names += 1;
+    });
+    assert_eq!(names, 1);
+}
+
+#[test_case]
+fn serial_is_registered() {
+    assert!(irq::is_requested(4));
+    let mut names = 0;
+    irq::for_each_handler(irq::FIRST_ISA_VECTOR + 4, |name| {
+        assert_eq!(name, "serial");
+        names += 1;
+    });
+    assert_eq!(names, 1);
+}
+
+#[test_case]
+fn handler_receives_context() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let vector = irq::allocate_vector().unwrap();
+    assert_eq!(vector, VECTOR);
+    let context = &CALLS as *const AtomicUsize as *mut ();
+    let handler = irq::register(vector, "test", count_calls, context).unwrap();
+
+    let before = irq::count(vector);
+    raise();
+    raise();
+    assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    assert_eq!(irq::count(vector), before + 2);
+
+    irq::free_handler(handler).unwrap();
+    raise();
+    assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    assert_eq!(irq::free_handler(handler), Err(IrqError::NotRegistered));
+    irq::free_vector(vector).unwrap();
+}
+
+#[test_case]
+fn shared_vector_calls_every_handler() {
+    let vector = irq::allocate_vector().unwrap();
+    let first = Arc::new(AtomicUsize::new(0));
+    let second = Arc::new(AtomicUsize::new(0));
+    for calls in [first.clone(), second.clone()].iter().cloned() {
+        irq::register_with(vector, "shared", move || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            IrqReturn::Handled
+        })
+        .unwrap();
+    }
+
+    raise();
+    assert_eq!(first.load(Ordering::SeqCst), 1);
+    assert_eq!(second.load(Ordering::SeqCst), 1);
+
+    // 解除するとクロージャも解放される
+    irq::free_vector(vector).unwrap();
+    assert_eq!(Arc::strong_count(&first), 1);
+    assert_eq!(Arc::strong_count(&second), 1);
+}
+
+#[test_case]
+fn handler_can_free_itself() {
+    static HANDLER: Mutex<Option<HandlerId>> = Mutex::new(None);
+
+    let vector = irq::allocate_vector().unwrap();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let captured = calls.clone();
+    let handler = irq::register_with(vector, "self free", move || {
+        // 呼び出し中に自分の登録を解除し、その後もクロージャの値を使う
+        if let Some(handler) = HANDLER.lock().take() {
+            irq::free_handler(handler).unwrap();
+        }
+        captured.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::Handled
+    })
+    .unwrap();
+    *HANDLER.lock() = Some(handler);
+
+    raise();
+    raise();
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // クロージャは呼び出しが終わるまで解放されず、次の登録の操作で解放される
+    assert_eq!(Arc::strong_count(&calls), 2);
+    irq::free_vector(vector).unwrap();
+    assert_eq!(Arc::strong_count(&calls), 1);
+}
+
+#[test_case]
+fn unhandled_interrupt_is_spurious() {
+    let vector = irq::allocate_vector().unwrap();
+    irq::register(vector, "not mine", not_mine, core::ptr::null_mut()).unwrap();
+
+    let before = irq::spurious_count();
+    raise();
+    assert_eq!(irq::spurious_count(), before + 1);
+    irq::free_vector(vector).unwrap();
+}
+
+#[test_case]
+fn vectors_must_be_allocated() {
+    assert_eq!(
+        irq::register(VECTOR, "test", not_mine, core::ptr::null_mut()),
+        Err(IrqError::NotRegistered)
+    );
+    assert_eq!(
+        irq::register(
+            irq::FIRST_ISA_VECTOR,
+            "test",
+            not_mine,
+            core::ptr::null_mut()
+        ),
+        Err(IrqError::InvalidVector)
+    );
+    assert_eq!(
+        irq::request_irq(irq::ISA_IRQ_COUNT, "test", not_mine, core::ptr::null_mut()),
+        Err(IrqError::InvalidIrq)
+    );
+}
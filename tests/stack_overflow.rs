@@ -1,13 +1,25 @@
-// stack_overflowにより発生するダブルフォルトハンドラの呼び出し確認
+// スタックオーバーフローがガードページで検出されることの確認
+// ガードページ付きのカーネルスタックを溢れさせると、ページフォルトのハンドラを呼べずにダブルフォルトになる
+// 続けてダブルフォルトのハンドラの中で再帰し、ISTのスタック自身のガードページも確かめる
 #![no_std]
 #![no_main]
 // 結合テストは完全に分けられた実行ファイルなので再記述
 #![feature(abi_x86_interrupt)]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use jura_os::{exit_qemu, serial_println, QemuExitCode};
+use core::sync::atomic::{AtomicU8, Ordering};
+use jura_os::memory::{self, stack::KernelStack, vmalloc};
+use jura_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// 0: カーネルスタックを溢れさせている, 1: ダブルフォルトのISTのスタックを溢れさせている
+static PHASE: AtomicU8 = AtomicU8::new(0);
 
 lazy_static! {
     // idtの用意
@@ -19,6 +31,8 @@ lazy_static! {
                 .set_handler_fn(test_double_fault_handler)
                 .set_stack_index(jura_os::gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        // カーネルと同じく、ページフォルトはISTを使わない
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
 
         idt
     };
@@ -28,13 +42,24 @@ pub fn init_test_idt() {
     TEST_IDT.load();
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    serial_println!("stack_overflow::stack_overflow...\t");
-
+fn main(boot_info: &'static BootInfo) -> ! {
     jura_os::gdt::init();
     init_test_idt();
 
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+    vmalloc::init();
+    jura_os::gdt::init_stacks();
+
+    serial_print!("stack_overflow::kernel_stack_overflow...\t");
+    let stack = KernelStack::new("test", 4).expect("failed to allocate a kernel stack");
+    unsafe { stack.switch_to(overflow_entry) };
+}
+
+extern "C" fn overflow_entry() -> ! {
     stack_overflow();
 
     panic!("Execution continued after stack overflow");
@@ -55,11 +80,37 @@ fn stack_overflow() {
     volatile::Volatile::new(0).read();
 }
 
+extern "x86-interrupt" fn test_page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    panic!("unexpected page fault\n{:#?}", stack_frame);
+}
+
 extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-    loop {}
+    // CR2にはページフォルトを起こしたガードページのアドレスが残っている
+    let overflowed = memory::stack::overflowed_stack(Cr2::read());
+    match PHASE.load(Ordering::SeqCst) {
+        0 => {
+            assert_eq!(overflowed, Some("test"));
+            serial_println!("[ok]");
+
+            // ISTのスタックの上で再帰する
+            // ガードページでページフォルトを積めずに再びダブルフォルトになり、
+            // ハンドラはISTのスタックの先頭からやり直す
+            serial_print!("stack_overflow::double_fault_stack_overflow...\t");
+            PHASE.store(1, Ordering::SeqCst);
+            stack_overflow();
+            panic!("Execution continued after stack overflow");
+        }
+        _ => {
+            assert_eq!(overflowed, Some("double fault"));
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+            loop {}
+        }
+    }
 }
@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jura_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::cell::RefCell;
+use core::panic::PanicInfo;
+use core::time::Duration;
+use jura_os::task::executor::Executor;
+use jura_os::task::timer::{self, Elapsed};
+use jura_os::task::Task;
+use jura_os::time::Instant;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use jura_os::allocator;
+    use jura_os::memory::{self, GlobalFrameAllocator};
+
+    jura_os::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+    memory::with_mapper(|mapper| allocator::init_heap(mapper, &mut GlobalFrameAllocator))
+        .expect("heap intialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jura_os::test_panic_handler(&info);
+}
+
+#[test_case]
+fn sleep_waits_for_duration() {
+    let start = Instant::now();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(timer::sleep(Duration::from_millis(20))));
+    executor.run_until_complete();
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn sleepers_wake_in_deadline_order() {
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for &(id, ms) in &[(3, 30), (1, 10), (2, 20)] {
+        let order = order.clone();
+        executor.spawn(Task::new(async move {
+            timer::sleep(Duration::from_millis(ms)).await;
+            order.borrow_mut().push(id);
+        }));
+    }
+    executor.run_until_complete();
+    assert_eq!(*order.borrow(), [1, 2, 3]);
+}
+
+#[test_case]
+fn interval_ticks_periodically() {
+    let start = Instant::now();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let mut interval = timer::interval(Duration::from_millis(5));
+        let mut last = Instant::now();
+        for _ in 0..4 {
+            let tick = interval.tick().await;
+            assert!(tick > last);
+            last = tick;
+        }
+    }));
+    executor.run_until_complete();
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn timeout_expires_and_completes() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let slow = timer::sleep(Duration::from_millis(50));
+        assert_eq!(
+            timer::timeout(slow, Duration::from_millis(5)).await,
+            Err(Elapsed)
+        );
+
+        let fast = async { 42 };
+        assert_eq!(timer::timeout(fast, Duration::from_millis(5)).await, Ok(42));
+    }));
+    executor.run_until_complete();
+}
@@ -0,0 +1,155 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(jura_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use jura_os::memory::vmalloc::{self, VmallocError};
+use jura_os::memory::{self, with_frame_allocator};
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    jura_os::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+    vmalloc::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    jura_os::test_panic_handler(&info);
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_mapper(|mapper| mapper.translate_addr(addr).is_some())
+}
+
+#[test_case]
+fn regions_do_not_overlap() {
+    let a = vmalloc::vmalloc(3 * 4096, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    let b = vmalloc::vmalloc(4096, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    assert!(a.end() <= b.start() || b.end() <= a.start());
+
+    unsafe {
+        a.as_mut_ptr::<u64>()
+            .write_bytes(0xaa, (a.size() / 8) as usize);
+        b.as_mut_ptr::<u64>()
+            .write_bytes(0x55, (b.size() / 8) as usize);
+        assert_eq!(*a.as_mut_ptr::<u8>().add(a.size() as usize - 1), 0xaa);
+        assert_eq!(*b.as_mut_ptr::<u8>(), 0x55);
+    }
+
+    vmalloc::vfree(a).expect("vfree failed");
+    vmalloc::vfree(b).expect("vfree failed");
+}
+
+#[test_case]
+fn guard_pages_are_unmapped() {
+    let region =
+        vmalloc::vmalloc_guarded(2 * 4096, 2, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    assert!(is_mapped(region.start()));
+    assert!(is_mapped(region.end() - 1u64));
+    assert!(!is_mapped(region.start() - 1u64));
+    assert!(!is_mapped(region.start() - 2 * 4096u64));
+    assert!(!is_mapped(region.end()));
+    assert!(!is_mapped(region.end() + 4096u64));
+    vmalloc::vfree(region).expect("vfree failed");
+}
+
+#[test_case]
+fn vfree_returns_frames_and_addresses() {
+    // ページテーブルのフレームは解放されないので、先に作らせておく
+    let warm_up = vmalloc::vmalloc(16 * 4096, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    vmalloc::vfree(warm_up).expect("vfree failed");
+
+    let free = with_frame_allocator(|allocator| allocator.free_frames());
+    let region = vmalloc::vmalloc(16 * 4096, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    vmalloc::vfree(region).expect("vfree failed");
+
+    assert!(!is_mapped(region.start()));
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.free_frames()),
+        free
+    );
+    match vmalloc::vfree(region) {
+        Err(VmallocError::NotAllocated) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // 解放したアドレスは再利用される
+    let again = vmalloc::vmalloc(16 * 4096, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    assert_eq!(again.start(), region.start());
+    vmalloc::vfree(again).expect("vfree failed");
+}
+
+#[test_case]
+fn physical_mapping_keeps_page_offset() {
+    let phys = PhysAddr::new(0xb8000 + 0x10);
+    let region = unsafe { vmalloc::map_physical(phys, 64, PageTableFlags::WRITABLE) }
+        .expect("map_physical failed");
+    let translated = memory::with_mapper(|mapper| mapper.translate_addr(region.start()));
+    assert_eq!(translated, Some(phys));
+
+    // 物理フレームは解放されない
+    let free = with_frame_allocator(|allocator| allocator.free_frames());
+    vmalloc::vfree(region).expect("vfree failed");
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.free_frames()),
+        free
+    );
+}
+
+#[test_case]
+fn lazy_region_is_mapped_on_first_touch() {
+    let region =
+        vmalloc::vmalloc_lazy(64 * 4096, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    assert!(!is_mapped(region.start()));
+
+    let free = with_frame_allocator(|allocator| allocator.free_frames());
+    let ptr = unsafe { region.as_mut_ptr::<u64>().add(5 * 4096 / 8) };
+    // 最初の読み込みでゼロのページがマップされる
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    unsafe { ptr.write_volatile(42) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 42);
+
+    assert!(is_mapped(VirtAddr::from_ptr(ptr)));
+    assert!(!is_mapped(region.start()));
+    // 触れたページの分だけフレームを使う (ページテーブルの分を許す)
+    let used = free - with_frame_allocator(|allocator| allocator.free_frames());
+    assert!(used >= 1 && used <= 3);
+
+    vmalloc::vfree(region).expect("vfree failed");
+}
+
+#[test_case]
+fn lazy_region_returns_touched_frames() {
+    let region = vmalloc::vmalloc_lazy(8 * 4096, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    // ページテーブルを作らせておく
+    unsafe { region.as_mut_ptr::<u8>().write_volatile(1) };
+
+    let free = with_frame_allocator(|allocator| allocator.free_frames());
+    for page in 1..8 {
+        unsafe { region.as_mut_ptr::<u8>().add(page * 4096).write_volatile(1) };
+    }
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.free_frames()),
+        free - 7
+    );
+
+    vmalloc::vfree(region).expect("vfree failed");
+    assert_eq!(
+        with_frame_allocator(|allocator| allocator.free_frames()),
+        free + 1
+    );
+}
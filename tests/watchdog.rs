@@ -0,0 +1,124 @@
+// watchdogが割り込みの嵐と、戻ってこないタスクのpollを見つけることの確認
+// 戻ってこないpollはpanicで終わるので、最後にpanicハンドラで確かめる
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr;
+use core::time::Duration;
+use jura_os::interrupts::irq::{self, IrqReturn};
+use jura_os::task::{executor::Executor, Task};
+use jura_os::watchdog::{self, Action};
+use jura_os::{exit_qemu, serial_print, serial_println, time, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use jura_os::allocator;
+    use jura_os::memory::{self, GlobalFrameAllocator};
+
+    jura_os::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_mapper(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map, physical_memory_offset);
+    }
+    memory::with_mapper(|mapper| allocator::init_heap(mapper, &mut GlobalFrameAllocator))
+        .expect("heap intialization failed");
+
+    interrupt_storm_is_reported();
+    hung_task_panics()
+}
+
+fn handled(_context: *mut ()) -> IrqReturn {
+    IrqReturn::Handled
+}
+
+fn interrupt_storm_is_reported() {
+    const VECTOR: u8 = irq::FIRST_DYNAMIC_VECTOR;
+    serial_print!("watchdog::interrupt_storm_is_reported...\t");
+
+    // タイマ割り込み (1000回/秒) は嵐とみなさない
+    watchdog::set_storm_threshold(1500);
+    assert_eq!(irq::allocate_vector(), Ok(VECTOR));
+    let id = irq::register(VECTOR, "storm", handled, ptr::null_mut()).unwrap();
+
+    let (_, before) = watchdog::detected();
+    for _ in 0..5000 {
+        unsafe { asm!("int {}", const VECTOR) };
+    }
+    // 嵐を含む区間が必ず終わるまで待つ
+    let start = time::ticks();
+    while time::ticks_to_ms(time::ticks() - start) < 2100 {
+        x86_64::instructions::hlt();
+    }
+    let (_, after) = watchdog::detected();
+    assert!(after > before);
+
+    irq::free_handler(id).unwrap();
+    irq::free_vector(VECTOR).unwrap();
+    serial_println!("[ok]");
+}
+
+fn hung_task_panics() -> ! {
+    serial_print!("watchdog::hung_task_panics...\t");
+
+    watchdog::set_action(Action::Panic);
+    watchdog::set_hung_task_timeout(Duration::from_millis(200));
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        loop {
+            core::hint::spin_loop();
+        }
+    }));
+    executor.run()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Buffer::new();
+    let _ = write!(message, "{}", info);
+    if message.as_str().contains("has not returned from poll") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+// panicのメッセージを比べるための、ヒープを使わない文字列
+struct Buffer {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl Buffer {
+    fn new() -> Self {
+        Buffer {
+            bytes: [0; 256],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}